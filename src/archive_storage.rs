@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+use tokio::sync::mpsc;
+use crate::storage::{ChunkReader, FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+use crate::utils::normalize_path;
+
+/// Size of the chunks of the files sent from the decompressing thread
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Format of an archive, from the extension of its file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read-only storage serving the content of a zip or tar archive of the local filesystem.
+/// The entries are indexed when the archive is opened, and the files are extracted as they
+/// are downloaded.
+pub struct ArchiveStorage {
+    archive: PathBuf,
+    kind: ArchiveKind,
+    /// Metadata of the entries by virtual path, along with the directories missing from the archive
+    entries: BTreeMap<PathBuf, FileMetadata>,
+}
+
+impl ArchiveStorage {
+    pub async fn open(archive: PathBuf) -> io::Result<Self> {
+        let kind = ArchiveKind::from_path(&archive)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a zip or tar archive"))?;
+        let path = archive.clone();
+        let entries = tokio::task::spawn_blocking(move || index(&path, kind)).await.map_err(io::Error::other)??;
+        Ok(ArchiveStorage { archive, kind, entries })
+    }
+
+    fn read_only() -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, "Archives are read-only")
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ArchiveStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.entries.get(&normalize_path(path)).cloned().ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        let path = normalize_path(path);
+        if !self.metadata(&path).await?.is_dir {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        Ok(self.entries.iter()
+            .filter(|(entry, _)| entry.parent() == Some(path.as_path()))
+            .map(|(entry, metadata)| FileEntry {
+                name: entry.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                metadata: metadata.clone(),
+            })
+            .collect())
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let path = normalize_path(path);
+        if self.metadata(&path).await?.is_dir {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+
+        let (sender, receiver) = mpsc::channel(4);
+        let (archive, kind) = (self.archive.clone(), self.kind);
+        tokio::task::spawn_blocking(move || {
+            let mut chunks = ChunkSender(sender.clone());
+            if let Err(err) = extract(&archive, kind, &path, offset, &mut chunks) {
+                sender.blocking_send(Err(err)).ok();
+            }
+        });
+        Ok(Box::new(ChunkReader::new(receiver)))
+    }
+
+    async fn open_write(&self, _path: &Path, _append: bool, _create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        Err(Self::read_only())
+    }
+
+    async fn mkdir(&self, _path: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn remove(&self, _path: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn set_mtime(&self, _path: &Path, _modified: SystemTime) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+}
+
+/// Function to read the entries of an archive, dated with the archive when they lack a date
+fn index(archive: &Path, kind: ArchiveKind) -> io::Result<BTreeMap<PathBuf, FileMetadata>> {
+    let modified = std::fs::metadata(archive)?.modified()?;
+    let mut entries = BTreeMap::new();
+    entries.insert(PathBuf::from("/"), FileMetadata::dir(modified));
+
+    let mut add = |name: &Path, is_dir: bool, size: u64, entry_modified: Option<SystemTime>| {
+        let path = normalize_path(&Path::new("/").join(name));
+        for parent in path.ancestors().skip(1) {
+            entries.entry(parent.to_path_buf()).or_insert_with(|| FileMetadata::dir(modified));
+        }
+        if path != Path::new("/") {
+            let entry_modified = entry_modified.unwrap_or(modified);
+            entries.insert(path, if is_dir { FileMetadata::dir(entry_modified) } else { FileMetadata::file(size, entry_modified) });
+        }
+    };
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?)).map_err(io::Error::other)?;
+            for index in 0..zip.len() {
+                let file = zip.by_index_raw(index).map_err(io::Error::other)?;
+                let entry_modified = file.last_modified().and_then(|date| {
+                    NaiveDate::from_ymd_opt(date.year().into(), date.month().into(), date.day().into())?
+                        .and_hms_opt(date.hour().into(), date.minute().into(), date.second().into())
+                        .map(|date| SystemTime::from(date.and_utc()))
+                });
+                add(Path::new(file.name()), file.is_dir(), file.size(), entry_modified);
+            }
+        },
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = tar::Archive::new(tar_reader(archive, kind)?);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                let header = entry.header();
+                let is_dir = header.entry_type().is_dir();
+                // links and special files are left out
+                if !is_dir && !header.entry_type().is_file() {
+                    continue;
+                }
+                let entry_modified = header.mtime().ok().map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+                add(&entry.path()?, is_dir, header.size()?, entry_modified);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn tar_reader(archive: &Path, kind: ArchiveKind) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(archive)?);
+    Ok(match kind {
+        ArchiveKind::TarGz => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file)
+    })
+}
+
+/// Function to write the content of the file of an archive at a virtual path, starting at `offset`
+fn extract(archive: &Path, kind: ArchiveKind, path: &Path, offset: u64, out: &mut impl Write) -> io::Result<()> {
+    let mut copy = |entry: &mut dyn Read| -> io::Result<()> {
+        io::copy(&mut entry.take(offset), &mut io::sink())?;
+        io::copy(entry, out)?;
+        Ok(())
+    };
+    let matches = |name: &Path| normalize_path(&Path::new("/").join(name)) == path;
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?)).map_err(io::Error::other)?;
+            for index in 0..zip.len() {
+                let mut file = zip.by_index(index).map_err(io::Error::other)?;
+                if !file.is_dir() && matches(Path::new(file.name())) {
+                    return copy(&mut file);
+                }
+            }
+        },
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = tar::Archive::new(tar_reader(archive, kind)?);
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_file() && matches(&entry.path()?) {
+                    return copy(&mut entry);
+                }
+            }
+        }
+    }
+    Err(io::ErrorKind::NotFound.into())
+}
+
+/// Writer sending the extracted data to the reader of the download
+struct ChunkSender(mpsc::Sender<io::Result<Vec<u8>>>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let size = data.len().min(CHUNK_SIZE);
+        self.0.blocking_send(Ok(data[..size].to_vec())).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_archive_storage() {
+    use flate2::write::GzEncoder;
+    use tokio::io::AsyncReadExt;
+
+    let base = std::env::temp_dir().join(format!("ftp-rustified-archives-{}", std::process::id()));
+    std::fs::create_dir_all(&base).unwrap();
+    let content = b"Release notes".repeat(10000);
+
+    let mut zip = zip::ZipWriter::new(File::create(base.join("release.zip")).unwrap());
+    zip.add_directory("docs/", zip::write::SimpleFileOptions::default()).unwrap();
+    zip.start_file("docs/notes.txt", zip::write::SimpleFileOptions::default()).unwrap();
+    zip.write_all(&content).unwrap();
+    zip.start_file("bin/tool", zip::write::SimpleFileOptions::default()).unwrap();
+    zip.write_all(b"#!/bin/sh").unwrap();
+    zip.finish().unwrap();
+
+    let mut tar = tar::Builder::new(GzEncoder::new(File::create(base.join("release.tar.gz")).unwrap(), flate2::Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(1700000000);
+    tar.append_data(&mut header, "./docs/notes.txt", content.as_slice()).unwrap();
+    tar.into_inner().unwrap().finish().unwrap();
+
+    for name in ["release.zip", "release.tar.gz"] {
+        let storage = ArchiveStorage::open(base.join(name)).await.unwrap();
+        assert!(storage.metadata(Path::new("/docs")).await.unwrap().is_dir, "{}", name);
+        assert_eq!(storage.metadata(Path::new("/docs/notes.txt")).await.unwrap().size, content.len() as u64);
+        assert_eq!(storage.list(Path::new("/docs")).await.unwrap()[0].name, "notes.txt");
+
+        let mut data = vec![];
+        storage.open_read(Path::new("/docs/notes.txt"), 0).await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content);
+        let mut data = vec![];
+        storage.open_read(Path::new("/docs/notes.txt"), 8).await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, &content[8..]);
+
+        assert!(storage.open_read(Path::new("/docs/missing.txt"), 0).await.is_err());
+        assert!(storage.open_read(Path::new("/docs"), 0).await.is_err());
+        assert_eq!(storage.remove(Path::new("/docs/notes.txt")).await.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(storage.open_write(Path::new("/a.txt"), false, false).await.is_err());
+    }
+    let names: Vec<String> = ArchiveStorage::open(base.join("release.zip")).await.unwrap()
+        .list(Path::new("/")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, vec!["bin", "docs"]);
+
+    std::fs::remove_dir_all(base).unwrap();
+}
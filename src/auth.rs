@@ -0,0 +1,479 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use sha1::Sha1;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use crate::client::Result;
+use crate::error::FtpError;
+use crate::ftp_config::{AuthConfig, FtpConfig, StorageConfig};
+use crate::ftp_user::FtpUser;
+use crate::ip_filter::IpFilter;
+use crate::mount::Mount;
+use crate::quota::Quota;
+use crate::permissions::{PathPermissions, Permission, Permissions};
+use crate::utils::get_content;
+
+/// Profile of a user known to an `Authenticator`
+#[derive(Debug, Clone, Default)]
+pub struct UserProfile {
+    pub username: String,
+    pub is_admin: bool,
+    pub needs_password: bool,
+    pub anonymous: bool,
+    pub home: Option<PathBuf>,
+    /// Operations allowed to the user, `None` allowing all of them
+    pub permissions: Option<Permissions>,
+    pub path_permissions: Vec<PathPermissions>,
+    pub groups: Vec<String>,
+    pub mounts: Vec<Mount>,
+    pub ip_filter: IpFilter,
+    pub disabled: bool,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub totp_secret: Option<String>,
+    /// Storage replacing the home directory
+    pub storage: Option<StorageConfig>,
+    pub quota: Option<Quota>,
+}
+
+impl UserProfile {
+    /// Checking if the account can be used at the given time
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        !self.disabled
+            && self.valid_from.is_none_or(|valid_from| valid_from <= now)
+            && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// Backend used by the `Client` to look up users and verify their passwords
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the profile of the user, or `None` if the user is unknown
+    async fn lookup(&self, username: &str) -> Result<Option<UserProfile>>;
+
+    /// Returns the profile of the user if the password is valid
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<UserProfile>>;
+}
+
+/// Function to create the authenticator selected in the config, defaulting to the JSON users
+pub fn new_authenticator(config: &FtpConfig) -> Arc<dyn Authenticator> {
+    let authenticator: Arc<dyn Authenticator> = match config.auth {
+        None | Some(AuthConfig::Json) => Arc::new(JsonAuthenticator::new(config)),
+        Some(AuthConfig::Htpasswd { ref path, ref admins }) => Arc::new(HtpasswdAuthenticator {
+            path: path.clone(),
+            admins: admins.clone(),
+        }),
+        Some(AuthConfig::Command { ref program, ref args, timeout_secs }) => Arc::new(CommandAuthenticator {
+            program: program.clone(),
+            args: args.clone(),
+            timeout: Duration::from_secs(timeout_secs),
+        }),
+    };
+
+    match config.anonymous {
+        Some(ref anonymous) => Arc::new(AnonymousAuthenticator {
+            inner: authenticator,
+            root: anonymous.root.clone(),
+            incoming: anonymous.incoming.clone(),
+        }),
+        None => authenticator,
+    }
+}
+
+/// Accepts the anonymous users with any password, delegating every other user to `inner`.
+/// Anonymous users can read `root` and only upload new files into `incoming`.
+pub struct AnonymousAuthenticator {
+    inner: Arc<dyn Authenticator>,
+    root: PathBuf,
+    incoming: Option<PathBuf>,
+}
+
+impl AnonymousAuthenticator {
+    pub const USERNAMES: [&'static str; 2] = ["anonymous", "ftp"];
+
+    fn profile(&self, username: &str) -> UserProfile {
+        UserProfile {
+            username: username.to_string(),
+            needs_password: true,
+            anonymous: true,
+            home: Some(self.root.clone()),
+            permissions: Some(Permissions::read_only()),
+            path_permissions: self.incoming.iter()
+                .map(|incoming| PathPermissions {
+                    path: incoming.clone(),
+                    permissions: Permissions::new(vec![Permission::Write]),
+                })
+                .collect(),
+            ..UserProfile::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for AnonymousAuthenticator {
+    async fn lookup(&self, username: &str) -> Result<Option<UserProfile>> {
+        if Self::USERNAMES.contains(&username) {
+            return Ok(Some(self.profile(username)));
+        }
+        self.inner.lookup(username).await
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<UserProfile>> {
+        if Self::USERNAMES.contains(&username) {
+            return Ok(Some(self.profile(username)));
+        }
+        self.inner.authenticate(username, password).await
+    }
+}
+
+/// Authenticates against the `admin` and `users` of the JSON config
+pub struct JsonAuthenticator {
+    admin: Option<FtpUser>,
+    users: Vec<FtpUser>,
+}
+
+impl JsonAuthenticator {
+    pub fn new(config: &FtpConfig) -> Self {
+        JsonAuthenticator {
+            admin: config.admin.clone(),
+            users: config.users.clone(),
+        }
+    }
+
+    fn find(&self, username: &str) -> Option<(&FtpUser, bool)> {
+        if let Some(ref admin) = self.admin {
+            if admin.username == username {
+                return Some((admin, true));
+            }
+        }
+        self.users.iter().find(|user| user.username == username).map(|user| (user, false))
+    }
+
+    fn profile(user: &FtpUser, is_admin: bool) -> UserProfile {
+        UserProfile {
+            username: user.username.clone(),
+            is_admin,
+            needs_password: !user.password.is_empty(),
+            home: user.home.as_ref().map(PathBuf::from),
+            permissions: user.permissions.clone(),
+            path_permissions: user.path_permissions.clone(),
+            groups: user.groups.clone(),
+            ip_filter: user.ip_filter.clone(),
+            disabled: !user.enabled,
+            valid_from: user.valid_from,
+            expires_at: user.expires_at,
+            totp_secret: user.totp_secret.clone(),
+            storage: user.storage.clone(),
+            quota: user.quota,
+            ..UserProfile::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for JsonAuthenticator {
+    async fn lookup(&self, username: &str) -> Result<Option<UserProfile>> {
+        Ok(self.find(username).map(|(user, is_admin)| Self::profile(user, is_admin)))
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<UserProfile>> {
+        Ok(self.find(username)
+            .filter(|(user, _)| user.password == password)
+            .map(|(user, is_admin)| Self::profile(user, is_admin)))
+    }
+}
+
+/// Authenticates against an Apache htpasswd file, supporting bcrypt, `$apr1$` and `{SHA}` hashes.
+/// The file is read on every login so that changes apply without restarting the server.
+pub struct HtpasswdAuthenticator {
+    path: PathBuf,
+    admins: Vec<String>,
+}
+
+impl HtpasswdAuthenticator {
+    async fn find_hash(&self, username: &str) -> Result<Option<String>> {
+        let content = get_content(&self.path).await
+            .ok_or_else(|| FtpError::Msg(format!("Unable to read htpasswd file {}", self.path.display())))?;
+
+        Ok(content.lines()
+            .filter_map(|line| line.trim().split_once(':'))
+            .find(|(user, _)| *user == username)
+            .map(|(_, hash)| hash.to_string()))
+    }
+
+    fn profile(&self, username: &str) -> UserProfile {
+        UserProfile {
+            username: username.to_string(),
+            is_admin: self.admins.iter().any(|admin| admin == username),
+            needs_password: true,
+            ..UserProfile::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+    async fn lookup(&self, username: &str) -> Result<Option<UserProfile>> {
+        Ok(self.find_hash(username).await?.map(|_| self.profile(username)))
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<UserProfile>> {
+        Ok(self.find_hash(username).await?
+            .filter(|hash| verify_htpasswd(hash, password))
+            .map(|_| self.profile(username)))
+    }
+}
+
+/// Checks a password against a single htpasswd hash
+pub fn verify_htpasswd(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2y$") || hash.starts_with("$2b$") || hash.starts_with("$2a$") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        apr1_md5(password, salt) == hash
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        base64::engine::general_purpose::STANDARD.encode(Sha1::digest(password.as_bytes())) == digest
+    } else {
+        // crypt(3) and plain text entries are not supported
+        false
+    }
+}
+
+/// Apache's MD5 based crypt variant, as produced by `htpasswd -m`
+fn apr1_md5(password: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let mut ctx = Md5::new();
+    ctx.update(password);
+    ctx.update(MAGIC.as_bytes());
+    ctx.update(salt);
+
+    let alternate = Md5::new().chain_update(password).chain_update(salt).chain_update(password).finalize();
+    for chunk in password.chunks(16) {
+        ctx.update(&alternate[..chunk.len()]);
+    }
+
+    let mut i = password.len();
+    while i != 0 {
+        if i & 1 != 0 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+
+    let mut result = ctx.finalize();
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        if i & 1 != 0 { ctx.update(password) } else { ctx.update(result) }
+        if i % 3 != 0 { ctx.update(salt) }
+        if i % 7 != 0 { ctx.update(password) }
+        if i & 1 != 0 { ctx.update(result) } else { ctx.update(password) }
+        result = ctx.finalize();
+    }
+
+    let mut out = format!("{}{}$", MAGIC, String::from_utf8_lossy(salt));
+    let mut to64 = |mut value: u32, count: usize| {
+        for _ in 0..count {
+            out.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64((result[a] as u32) << 16 | (result[b] as u32) << 8 | result[c] as u32, 4);
+    }
+    to64(result[11] as u32, 2);
+
+    out
+}
+
+/// Authenticates by running an external program.
+/// The username and password are written to its stdin on separate lines, a zero exit status
+/// accepts the login and the program may print a JSON profile (`admin`, `home`, `permissions`,
+/// `path_permissions`, `groups`, `ip_filter`, `enabled`, `valid_from`, `expires_at`, `totp_secret`,
+/// `quota`).
+pub struct CommandAuthenticator {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CommandProfile {
+    #[serde(default)]
+    admin: bool,
+    home: Option<PathBuf>,
+    permissions: Option<Permissions>,
+    #[serde(default)]
+    path_permissions: Vec<PathPermissions>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    ip_filter: IpFilter,
+    /// Disabling the account with `false`, enabled when missing
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    valid_from: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    totp_secret: Option<String>,
+    quota: Option<Quota>,
+}
+
+#[async_trait]
+impl Authenticator for CommandAuthenticator {
+    /// The program is only consulted with a password, so every username is accepted here
+    async fn lookup(&self, username: &str) -> Result<Option<UserProfile>> {
+        Ok(Some(UserProfile {
+            username: username.to_string(),
+            needs_password: true,
+            ..UserProfile::default()
+        }))
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<UserProfile>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(format!("{}\n{}\n", username, password).as_bytes()).await?;
+        }
+
+        let output = match tokio::time::timeout(self.timeout, child.wait_with_output()).await {
+            Ok(output) => output?,
+            Err(_) => return Err(FtpError::Msg(format!("Authentication command timed out for {}", username))),
+        };
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let profile: CommandProfile = if stdout.trim().is_empty() {
+            CommandProfile::default()
+        } else {
+            serde_json::from_str(&stdout)
+                .map_err(|e| FtpError::Msg(format!("Invalid profile from authentication command: {}", e)))?
+        };
+
+        Ok(Some(UserProfile {
+            username: username.to_string(),
+            is_admin: profile.admin,
+            needs_password: true,
+            home: profile.home,
+            permissions: profile.permissions,
+            path_permissions: profile.path_permissions,
+            groups: profile.groups,
+            ip_filter: profile.ip_filter,
+            disabled: profile.enabled == Some(false),
+            valid_from: profile.valid_from,
+            expires_at: profile.expires_at,
+            totp_secret: profile.totp_secret,
+            quota: profile.quota,
+            ..UserProfile::default()
+        }))
+    }
+}
+
+#[test]
+fn test_verify_htpasswd() {
+    assert!(verify_htpasswd("$apr1$r31....$gnsoqlxyxQQ0Ot5JCwiei.", "secret"));
+    assert!(verify_htpasswd("$apr1$abcdefgh$aJUuGLjz3OI4ylHF//t5U1", "p@ss word"));
+    assert!(!verify_htpasswd("$apr1$r31....$gnsoqlxyxQQ0Ot5JCwiei.", "Secret"));
+    assert!(verify_htpasswd("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "secret"));
+    assert!(verify_htpasswd(&bcrypt::hash("secret", 4).unwrap(), "secret"));
+    assert!(!verify_htpasswd("secret", "secret"));
+}
+
+#[tokio::test]
+async fn test_json_authenticator() {
+    let config = FtpConfig::new("ftp_config_test.json").await.expect("File not Found");
+    let authenticator = new_authenticator(&config);
+
+    let admin = authenticator.lookup("admin").await.unwrap().expect("Admin user not found");
+    assert!(admin.is_admin);
+    assert!(admin.needs_password);
+    assert!(authenticator.lookup("nobody").await.unwrap().is_none());
+
+    let user = authenticator.authenticate("user", "user").await.unwrap().expect("Invalid password");
+    assert!(!user.is_admin);
+    assert!(authenticator.authenticate("user", "admin").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_command_authenticator() {
+    let authenticator = CommandAuthenticator {
+        program: "sh".to_string(),
+        args: vec![
+            "-c".to_string(),
+            r#"read user; read pass; [ "$pass" = "letmein" ] && echo "{\"home\": \"/srv/$user\"}""#.to_string(),
+        ],
+        timeout: Duration::from_secs(5),
+    };
+
+    let profile = authenticator.authenticate("partner", "letmein").await.unwrap().expect("Login rejected");
+    assert_eq!(profile.home, Some(PathBuf::from("/srv/partner")));
+    assert!(!profile.is_admin);
+    assert!(authenticator.authenticate("partner", "wrong").await.unwrap().is_none());
+
+    // the program can disable the account or delay its validity
+    let authenticator = CommandAuthenticator {
+        program: "sh".to_string(),
+        args: vec!["-c".to_string(), r#"echo '{"enabled": false, "valid_from": "2099-01-01T00:00:00Z"}'"#.to_string()],
+        timeout: Duration::from_secs(5),
+    };
+    let profile = authenticator.authenticate("partner", "letmein").await.unwrap().expect("Login rejected");
+    assert!(profile.disabled);
+    assert_eq!(profile.valid_from, Some("2099-01-01T00:00:00Z".parse().unwrap()));
+    assert!(!profile.is_active(Utc::now()));
+}
+
+#[tokio::test]
+async fn test_anonymous_authenticator() {
+    let mut config = FtpConfig::new("ftp_config_test.json").await.expect("File not Found");
+    config.anonymous = Some(crate::ftp_config::AnonymousConfig {
+        root: PathBuf::from("PUBLIC"),
+        incoming: Some(PathBuf::from("incoming")),
+    });
+    let authenticator = new_authenticator(&config);
+
+    let guest = authenticator.authenticate("anonymous", "guest@example.com").await.unwrap().expect("Guest rejected");
+    assert!(guest.anonymous);
+    assert_eq!(guest.home, Some(PathBuf::from("PUBLIC")));
+    assert_eq!(guest.permissions, Some(Permissions::read_only()));
+    assert_eq!(guest.path_permissions[0].permissions, Permissions::new(vec![Permission::Write]));
+    assert!(authenticator.lookup("ftp").await.unwrap().is_some());
+    assert!(authenticator.authenticate("user", "user").await.unwrap().is_some());
+    assert!(authenticator.authenticate("user", "guest@example.com").await.unwrap().is_none());
+}
+
+#[test]
+fn test_profile_is_active() {
+    let now = Utc::now();
+    let profile = UserProfile {
+        valid_from: Some(now - chrono::Duration::days(1)),
+        expires_at: Some(now + chrono::Duration::days(1)),
+        ..UserProfile::default()
+    };
+    assert!(profile.is_active(now));
+    assert!(!profile.is_active(now - chrono::Duration::days(2)));
+    assert!(!profile.is_active(now + chrono::Duration::days(1)));
+    assert!(!UserProfile { disabled: true, ..UserProfile::default() }.is_active(now));
+}
\ No newline at end of file
@@ -0,0 +1,325 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use async_trait::async_trait;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::AsyncReadExt;
+use crate::storage::{FileWriter, StorageBackend};
+
+/// Size of the chunks the files are read in to be hashed
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Extension of the sidecar files holding the SHA-256 of the file they are named after
+const SIDECAR_EXTENSION: &str = "sha256";
+
+/// Extended attribute holding the SHA-256 of a file
+const CHECKSUM_ATTRIBUTE: &str = "user.sha256";
+
+/// Algorithm of the checksums of `HASH` and of the `XCRC`, `XMD5`, `XSHA1` and `XSHA256` commands
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 5] = [HashAlgorithm::Crc32, HashAlgorithm::Md5, HashAlgorithm::Sha1, HashAlgorithm::Sha256, HashAlgorithm::Sha512];
+
+    /// Name of the algorithm in the `HASH` replies and in `OPTS HASH`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Crc32 => "CRC32",
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Sha1 => "SHA-1",
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha512 => "SHA-512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        HashAlgorithm::ALL.into_iter().find(|algorithm| algorithm.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn hasher(&self) -> Hasher {
+        match *self {
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Getting the checksum as lowercase hexadecimal
+    fn finish(self) -> String {
+        match self {
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+            Hasher::Md5(hasher) => hex(&hasher.finalize()),
+            Hasher::Sha1(hasher) => hex(&hasher.finalize()),
+            Hasher::Sha256(hasher) => hex(&hasher.finalize()),
+            Hasher::Sha512(hasher) => hex(&hasher.finalize()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Function to hash the bytes of a file from `start` up to `end` excluded, or to its end, streaming
+/// them from the storage
+pub async fn checksum(storage: &dyn StorageBackend, path: &Path, algorithm: HashAlgorithm, start: u64, end: Option<u64>) -> io::Result<String> {
+    let reader = storage.open_read(path, start).await?;
+    let mut reader = reader.take(end.map_or(u64::MAX, |end| end.saturating_sub(start)));
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let size = reader.read(&mut buffer).await?;
+        if size == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..size]);
+    }
+}
+
+/// Where the SHA-256 of the uploaded files is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumStore {
+    /// `<name>.sha256` file next to the upload, in the format of `sha256sum`
+    Sidecar,
+    /// `user.sha256` extended attribute of the upload, for the local storage
+    Xattr,
+}
+
+/// SHA-256 of the uploads, computed while they are received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadChecksums {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<ChecksumStore>,
+    /// Refusing the uploads not matching the `<name>.sha256` sidecar uploaded before them, if any
+    pub verify: bool,
+}
+
+impl UploadChecksums {
+    /// Hashing an upload to a path of a storage, checked against its sidecar when verifying
+    pub async fn writer(&self, inner: Box<dyn FileWriter>, storage: Arc<dyn StorageBackend>, path: &Path) -> Box<dyn FileWriter> {
+        let expected = match self.verify {
+            true => read_sidecar(storage.as_ref(), &sidecar_path(path)).await,
+            false => None
+        };
+        // sidecars aren't hashed themselves
+        let store = self.store.filter(|_| path.extension().is_none_or(|extension| extension != SIDECAR_EXTENSION));
+        if expected.is_none() && store.is_none() {
+            return inner;
+        }
+        Box::new(HashingFileWriter { inner, hasher: Sha256::new(), storage, path: path.to_path_buf(), expected, store })
+    }
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}", name, SIDECAR_EXTENSION))
+}
+
+/// Function to read the checksum of a sidecar, the first word of its content
+async fn read_sidecar(storage: &dyn StorageBackend, path: &Path) -> Option<String> {
+    let mut content = String::new();
+    storage.open_read(path, 0).await.ok()?.take(1024).read_to_string(&mut content).await.ok()?;
+    content.split_whitespace().next().map(str::to_lowercase)
+}
+
+/// Error of an upload not matching the checksum of its sidecar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChecksumMismatch;
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Checksum mismatch")
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Function to check if an upload failed for not matching its sidecar
+pub fn is_checksum_mismatch(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<ChecksumMismatch>())
+}
+
+/// Upload hashed as it is written, discarded when its checksum isn't the expected one and
+/// recorded once complete
+struct HashingFileWriter {
+    inner: Box<dyn FileWriter>,
+    hasher: Sha256,
+    storage: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    expected: Option<String>,
+    store: Option<ChecksumStore>,
+}
+
+#[async_trait]
+impl FileWriter for HashingFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.inner.write(data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        let HashingFileWriter { inner, hasher, storage, path, expected, store } = *self;
+        let checksum = hex(&hasher.finalize());
+        if expected.as_ref().is_some_and(|expected| *expected != checksum) {
+            inner.abort().await.ok();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ChecksumMismatch));
+        }
+        let size = inner.finish().await?;
+
+        // the upload is complete even when its checksum can't be recorded
+        let recorded = match store {
+            Some(ChecksumStore::Sidecar) if expected.as_ref() != Some(&checksum) => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let content = format!("{}  {}\n", checksum, name);
+                match storage.open_write(&sidecar_path(&path), false, false).await {
+                    Ok(mut writer) => match writer.write(content.as_bytes()).await {
+                        Ok(()) => writer.finish().await.map(|_| ()),
+                        Err(err) => Err(err)
+                    },
+                    Err(err) => Err(err)
+                }
+            },
+            Some(ChecksumStore::Xattr) => storage.set_attribute(&path, CHECKSUM_ATTRIBUTE, checksum.as_bytes()).await,
+            _ => Ok(())
+        };
+        if let Err(err) = recorded {
+            eprintln!("Unable to record the checksum of {}: {}", path.display(), err);
+        }
+        Ok(size)
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+/// Function to split the arguments of the `XCRC`-like commands, a file name optionally quoted to
+/// hold spaces, followed by the optional start and end of the range to hash
+pub fn parse_checksum_args(args: &str) -> (String, u64, Option<u64>) {
+    let args = args.trim();
+    let (name, rest) = match args.strip_prefix('"').and_then(|args| args.split_once('"')) {
+        Some((name, rest)) => (name.to_string(), rest.to_string()),
+        None => {
+            // the trailing numbers are the range, the rest is the name
+            let mut words: Vec<&str> = args.split(' ').collect();
+            let mut numbers = vec![];
+            while words.len() > 1 && numbers.len() < 2 && words.last().is_some_and(|word| word.parse::<u64>().is_ok()) {
+                numbers.insert(0, words.pop().unwrap_or_default());
+            }
+            (words.join(" "), numbers.join(" "))
+        }
+    };
+    let mut numbers = rest.split_whitespace().filter_map(|number| number.parse::<u64>().ok());
+    (name, numbers.next().unwrap_or(0), numbers.next())
+}
+
+#[tokio::test]
+async fn test_upload_checksums() {
+    use crate::memory_storage::MemoryStorage;
+
+    async fn upload(checksums: &UploadChecksums, storage: &Arc<dyn StorageBackend>, path: &str, data: &[u8]) -> io::Result<u64> {
+        let inner = storage.open_write(Path::new(path), false, false).await?;
+        let mut writer = checksums.writer(inner, storage.clone(), Path::new(path)).await;
+        writer.write(data).await?;
+        writer.finish().await
+    }
+
+    let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let checksums = UploadChecksums { store: Some(ChecksumStore::Sidecar), verify: true };
+    let hash = "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969";
+
+    upload(&checksums, &storage, "/a.txt", b"Hello").await.unwrap();
+    assert_eq!(read_sidecar(storage.as_ref(), Path::new("/a.txt.sha256")).await, Some(hash.to_string()));
+    assert!(storage.metadata(Path::new("/a.txt.sha256.sha256")).await.is_err());
+
+    // the sidecar uploaded first is checked
+    upload(&checksums, &storage, "/b.txt.sha256", format!("{}  b.txt\n", hash.to_uppercase()).as_bytes()).await.unwrap();
+    upload(&checksums, &storage, "/b.txt", b"Hello").await.unwrap();
+    let err = upload(&checksums, &storage, "/b.txt", b"Hello!").await.unwrap_err();
+    assert!(is_checksum_mismatch(&err));
+    assert!(!is_checksum_mismatch(&io::Error::other("network")));
+
+    // the memory storage has no extended attributes, which doesn't fail the upload
+    let checksums = UploadChecksums { store: Some(ChecksumStore::Xattr), verify: false };
+    assert_eq!(upload(&checksums, &storage, "/c.txt", b"Hello").await.unwrap(), 5);
+}
+
+#[test]
+fn test_parse_checksum_args() {
+    assert_eq!(parse_checksum_args("a.txt"), ("a.txt".to_string(), 0, None));
+    assert_eq!(parse_checksum_args("a.txt 10 20"), ("a.txt".to_string(), 10, Some(20)));
+    assert_eq!(parse_checksum_args("\"my file.txt\" 5"), ("my file.txt".to_string(), 5, None));
+    assert_eq!(parse_checksum_args("report 2024.csv"), ("report 2024.csv".to_string(), 0, None));
+    assert_eq!(parse_checksum_args("2024"), ("2024".to_string(), 0, None));
+}
+
+#[test]
+fn test_hash_algorithm() {
+    assert_eq!(HashAlgorithm::from_name("sha-256"), Some(HashAlgorithm::Sha256));
+    assert_eq!(HashAlgorithm::from_name("CRC32"), Some(HashAlgorithm::Crc32));
+    assert_eq!(HashAlgorithm::from_name("SHA256"), None);
+}
+
+#[tokio::test]
+async fn test_checksum() {
+    use crate::memory_storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    let mut writer = storage.open_write(Path::new("/a.txt"), false, false).await.unwrap();
+    writer.write(b"The quick brown fox jumps over the lazy dog").await.unwrap();
+    writer.finish().await.unwrap();
+
+    let path = Path::new("/a.txt");
+    assert_eq!(checksum(&storage, path, HashAlgorithm::Crc32, 0, None).await.unwrap(), "414fa339");
+    assert_eq!(checksum(&storage, path, HashAlgorithm::Md5, 0, None).await.unwrap(), "9e107d9d372bb6826bd81d3542a419d6");
+    assert_eq!(checksum(&storage, path, HashAlgorithm::Sha1, 0, None).await.unwrap(), "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+    assert_eq!(checksum(&storage, path, HashAlgorithm::Sha256, 0, None).await.unwrap(), "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592");
+
+    // the range of "quick"
+    assert_eq!(checksum(&storage, path, HashAlgorithm::Md5, 4, Some(9)).await.unwrap(), checksum_of(b"quick"));
+    assert!(checksum(&storage, Path::new("/missing"), HashAlgorithm::Md5, 0, None).await.is_err());
+
+    fn checksum_of(data: &[u8]) -> String {
+        hex(&Md5::digest(data))
+    }
+}
@@ -1,47 +1,112 @@
-use std::path::{Path, PathBuf, StripPrefixError};
-use std::{io, result};
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::{io, mem, result};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::os::unix::prelude::PermissionsExt;
-use tokio::fs::{create_dir, create_dir_all, read_dir, remove_dir_all, remove_file, File};
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use crate::archive_storage::{ArchiveKind, ArchiveStorage};
+use crate::auth::{Authenticator, UserProfile};
+use crate::checksum::{checksum, is_checksum_mismatch, parse_checksum_args, HashAlgorithm};
 use crate::client_command::{Command, DataTransferType};
+use crate::encryption::EncryptedStorage;
 use crate::error::FtpError;
+use crate::filename_policy::unique_name;
 use crate::ftp_config::FtpConfig;
 use crate::ftp_response_code::ResponseCode;
+use crate::ftp_group::apply_groups;
 use crate::ftp_response::Response;
-use crate::utils::{add_file_info, get_current_dir, get_file_info, get_filename, get_permissions, invalid_path, prefix_slash, CONFIG_FILE};
+use crate::login_guard::LoginGuard;
+use crate::mount::{MountPoint, MountedStorage};
+use crate::permissions::{permissions_for, Permission};
+use crate::quota::{LimitedFileWriter, Quota, QuotaStorage, UsageRegistry};
+use crate::storage::{new_storage, AtomicStorage, FileWriter, ReadOnlyStorage, StorageBackend, StorageRoot};
+use crate::totp;
+use crate::trash::{self, TrashRegistry, TrashStorage, TRASH_DIR};
+use crate::upload_policy::{policy_violation, UploadPolicy};
+use crate::versioning::{OverwritePolicy, VersioningStorage, VERSIONS_DIR};
+use crate::utils::{add_file_info, get_filename, get_first_word_and_rest, invalid_path, normalize_path, prefix_slash, resolve_home, CONFIG_FILE};
 
 pub type Result<T> = result::Result<T, FtpError>;
 
+/// Level of the compression of `MODE Z` until the client sets it, the default of zlib
+const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
 pub struct Client {
     cwd: PathBuf,
     data_port: Option<u16>,
     data_reader: Option<ReadHalf<TcpStream>>,
     data_writer: Option<WriteHalf<TcpStream>>,
     name: Option<String>,
-    server_root_dir: PathBuf,
+    storage_root: StorageRoot,
+    /// Home directory the session is confined to, in the storage root. `None` shares the whole root.
+    home: Option<PathBuf>,
+    /// Storage of the session, made of the home directory and the mounted folders
+    storage: Arc<dyn StorageBackend>,
     data_transfer_type: DataTransferType,
     writer: WriteHalf<TcpStream>,
     is_admin: bool,
-    ftp_config: FtpConfig,
-    waiting_password: bool
+    /// Config of the server, shared by the sessions
+    ftp_config: Arc<FtpConfig>,
+    waiting_password: bool,
+    authenticator: Arc<dyn Authenticator>,
+    /// Profile of the logged in user, boxed to keep the client small as every command moves it
+    profile: Option<Box<UserProfile>>,
+    /// Profile of the user whose password was accepted, waiting for its one-time code
+    pending_profile: Option<Box<UserProfile>>,
+    login_guard: Arc<LoginGuard>,
+    /// Trashes of the sessions, purged periodically by the server
+    trashes: Arc<TrashRegistry>,
+    /// Usages of the storages with a quota, shared with the other sessions
+    usages: Arc<UsageRegistry>,
+    peer_ip: IpAddr,
+    login_failures: u32,
+    /// Algorithm of the checksums of `HASH`, selected with `OPTS HASH`
+    hash_algorithm: HashAlgorithm,
+    /// Byte range set by `RANG` for the next `HASH`, its end included
+    range: Option<(u64, u64)>,
+    /// Compressing the data transfers, set with `MODE Z`
+    deflate: bool,
+    /// Level of the compression of `MODE Z`, set with `OPTS MODE Z LEVEL <level>`
+    compression_level: u32,
+    /// Compressor of the data being sent in `MODE Z`, finished at the end of the transfer
+    encoder: Option<ZlibEncoder<Vec<u8>>>,
+    closed: bool
 }
 
 impl Client {
-    pub fn new(writer: WriteHalf<TcpStream>, server_root_dir: PathBuf, ftp_config: FtpConfig) -> Self {
+    pub fn new(writer: WriteHalf<TcpStream>, storage_root: StorageRoot, ftp_config: Arc<FtpConfig>, authenticator: Arc<dyn Authenticator>, login_guard: Arc<LoginGuard>, trashes: Arc<TrashRegistry>, usages: Arc<UsageRegistry>, peer_ip: IpAddr) -> Self {
         Client {
             cwd: PathBuf::from("/"),
             data_port: None,
             data_reader: None,
             data_writer: None,
             name: None,
-            server_root_dir,
+            home: None,
+            storage: storage_root.open(Path::new("")),
+            storage_root,
             data_transfer_type: DataTransferType::ASCII,
             writer,
             is_admin: false,
             ftp_config,
-            waiting_password: false
+            waiting_password: false,
+            authenticator,
+            profile: None,
+            pending_profile: None,
+            login_guard,
+            trashes,
+            usages,
+            peer_ip,
+            login_failures: 0,
+            hash_algorithm: HashAlgorithm::default(),
+            range: None,
+            deflate: false,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            encoder: None,
+            closed: false
         }
     }
 
@@ -49,12 +114,33 @@ impl Client {
         self.name.is_some() && !self.waiting_password
     }
 
+    /// Checking if the server has to close the connection
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Getting the expiration time of the logged in account
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        if self.is_logged_in() {
+            self.profile.as_ref().and_then(|profile| profile.expires_at)
+        } else {
+            None
+        }
+    }
+
+    /// Closing the session of an account which expired
+    pub async fn expire(self) -> Result<Self> {
+        eprintln!("Account of {:?} expired, closing the connection", &self.name);
+        self.close(Response::new(ResponseCode::ServiceNotAvailable, "Account expired, closing connection\r\n")).await
+    }
+
     pub async fn handle_command(mut self, cmd: Command) -> Result<Self> {
         println!("-> COMMAND: {:?}", &cmd);
         if self.is_logged_in() {
             match cmd {
                 Command::CWD(directory) => return Ok(self.handle_cwd(directory).await?),
-                Command::LIST(args) => return Ok(self.list(args).await?),
+                // the futures of the transfers are large, they are kept on the heap
+                Command::LIST(args) => return Ok(Box::pin(self.list(args)).await?),
                 Command::PASV => return Ok(self.pasv().await?),
                 Command::PORT(port) => {
                     self.data_port = Some(port);
@@ -70,11 +156,14 @@ impl Client {
                         return Ok(self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await?);
                     }
                 },
-                Command::RETR(file) => return Ok(self.retr(file).await?),
+                Command::RETR(file) => return Ok(Box::pin(self.retr(file)).await?),
                 Command::STOR(file) => {
                     println!("XXX STOR path: {:?}", &file);
-                    return Ok(self.stor(file).await?)
+                    return Ok(Box::pin(self.stor(file, false)).await?)
                 },
+                // without a name, the unique names are based on `upload`
+                Command::STOU(file) if file.as_os_str().is_empty() => return Ok(Box::pin(self.stor(PathBuf::from("upload"), true)).await?),
+                Command::STOU(file) => return Ok(Box::pin(self.stor(file, true)).await?),
                 Command::CDUP => {
                     if let Some(path) = self.cwd.parent().map(Path::to_path_buf) {
                         self.cwd = path;
@@ -86,39 +175,107 @@ impl Client {
                 Command::MKD(path) => return Ok(self.mkd(path).await?),
                 Command::RMD(path) => return Ok(self.rmd(path).await?),
                 Command::SIZE(path) => return Ok(self.get_size(path).await?),
+                Command::MFMT(args) => return Ok(self.mfmt(args).await?),
+                Command::SITE(args) => return Ok(self.site(args).await?),
+                Command::HASH(path) => return Ok(self.hash(path).await?),
+                Command::RANG(args) => return Ok(self.rang(args).await?),
+                Command::XCRC(args) => return Ok(self.x_checksum(HashAlgorithm::Crc32, args).await?),
+                Command::XMD5(args) => return Ok(self.x_checksum(HashAlgorithm::Md5, args).await?),
+                Command::XSHA1(args) => return Ok(self.x_checksum(HashAlgorithm::Sha1, args).await?),
+                Command::XSHA256(args) => return Ok(self.x_checksum(HashAlgorithm::Sha256, args).await?),
+                Command::XSHA512(args) => return Ok(self.x_checksum(HashAlgorithm::Sha512, args).await?),
                 _ => ()
             }
         } else if self.name.is_some() && self.waiting_password {
-            if let Command::PASS(content) = cmd {
-                let mut ok = false;
-                if self.is_admin {
-                    ok = content == self.ftp_config.admin.as_ref().unwrap().password;
-                } else {
-                    for user in &self.ftp_config.users {
-                        if Some(&user.username) == self.name.as_ref() {
-                            if user.password == content {
-                                ok = true;
-                                break;
+            if let Command::PASS(_) | Command::ACCT(_) = cmd {
+                let name = self.name.clone().unwrap_or_default();
+                if self.login_guard.is_ip_banned(self.peer_ip) || self.login_guard.is_user_banned(&name) {
+                    return self.close(Response::new(ResponseCode::ServiceNotAvailable, "Too many failed logins, try again later\r\n")).await;
+                }
+            }
+
+            match cmd {
+                Command::PASS(content) => {
+                    let name = self.name.clone().unwrap_or_default();
+
+                    // the users without a password send their one-time code as the password
+                    if let Some(profile) = self.pending_profile.take_if(|profile| !profile.needs_password) {
+                        if Self::verify_code(&profile, &content) {
+                            self.login_guard.record_success(&name);
+                            return self.login(*profile).await;
+                        }
+                        self.pending_profile = Some(profile);
+                        return self.login_failed(&name).await;
+                    }
+                    self.pending_profile = None;
+
+                    let checked = match self.check_password(&name, &content).await {
+                        Ok(checked) => checked,
+                        Err(err) => return self.authentication_unavailable(&name, err).await
+                    };
+                    match checked {
+                        Some((profile, true)) => {
+                            self.pending_profile = Some(Box::new(profile));
+                            self = self.send_response(Response::new(ResponseCode::NeedAccountForLogin, "Send your one-time code with ACCT\r\n")).await?;
+                        },
+                        Some((profile, false)) => {
+                            self.login_guard.record_success(&name);
+                            self = self.login(profile).await?;
+                        },
+                        None => self = self.login_failed(&name).await?
+                    }
+                    return Ok(self);
+                },
+                Command::ACCT(code) => {
+                    let name = self.name.clone().unwrap_or_default();
+
+                    match self.pending_profile.take() {
+                        Some(profile) => {
+                            if Self::verify_code(&profile, &code) {
+                                self.login_guard.record_success(&name);
+                                self = self.login(*profile).await?;
+                            } else {
+                                self.pending_profile = Some(profile);
+                                self = self.login_failed(&name).await?;
                             }
+                        },
+                        None => {
+                            self = self.send_response(Response::new(ResponseCode::BadSequenceOfCommands, "Send your password first\r\n")).await?;
                         }
                     }
-                }
-                if ok {
-                    self.waiting_password = false;
-                    let name = self.name.clone().unwrap_or(String::new());
-                    self = self.send_response(Response::new(ResponseCode::UserLoggedIn, &format!("Welcome {}!\t\n", name))).await?;
-                } else {
-                    self = self.send_response(Response::new(ResponseCode::NotLoggedIn, "Invalid Password\r\n")).await?;
-                }
-                return Ok(self);
+                    return Ok(self);
+                },
+                _ => ()
             }
         }
         match cmd {
             Command::AUTH => self = self.send_response(Response::new(ResponseCode::CommandNotImplemented, "Not Implemented\r\n")).await?,
             Command::QUIT => self = self.quit().await?,
+            Command::REIN => self = self.reinitialize().await?,
+            Command::ACCT(_) => {
+                if self.is_logged_in() {
+                    self = self.send_response(Response::new(ResponseCode::CommandNotImplementedSuperfluousAtThisSite, "No account needed\r\n")).await?;
+                } else {
+                    self = self.send_response(Response::new(ResponseCode::BadSequenceOfCommands, "Login with USER first\r\n")).await?;
+                }
+            },
             Command::SYST => {
                 self = self.send_response(Response::new(ResponseCode::Ok, "Bugger Off\r\n")).await?;
             },
+            Command::MODE(mode) => {
+                let reply = match mode.trim().to_uppercase().as_str() {
+                    "S" => {
+                        self.deflate = false;
+                        Response::new(ResponseCode::Ok, "Mode set to S\r\n")
+                    },
+                    "Z" => {
+                        self.deflate = true;
+                        Response::new(ResponseCode::Ok, "Mode set to Z\r\n")
+                    },
+                    _ => Response::new(ResponseCode::CommandNotImplementedForThatParameter, "Only the S and Z modes are supported\r\n")
+                };
+                self = self.send_response(reply).await?;
+            },
             Command::TYPE(type_) => {
                 self.data_transfer_type = type_;
                 self = self.send_response(Response::new(ResponseCode::Ok, "Data Transfer Type Changed Successfully\r\n")).await?;
@@ -127,45 +284,45 @@ impl Client {
                 if content.is_empty() {
                     self = self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Invalid Username\r\n")).await?;
                 } else {
-                    let mut name = None;
-                    let mut password_req = true;
-
                     self.is_admin = false;
+                    self.profile = None;
+                    self.pending_profile = None;
 
-                    if let Some(ref admin) = self.ftp_config.admin {
-                        if admin.username == content {
-                            name = Some(content.clone());
-                            password_req = admin.password.is_empty() == false;
-                            self.is_admin = true;
-                        }
-                    }
-
-                    if name.is_none() {
-                        for user in &self.ftp_config.users {
-                            if user.username == content {
-                                name = Some(content.clone());
-                                password_req = user.password.is_empty() == false;
-                                break;
-                            }
-                        }
-                    }
-
-                    if name.is_none() {
-                        self = self.send_response(Response::new(ResponseCode::NotLoggedIn, "Unknown User!\r\n")).await?;
-                    } else {
-                        self.name = name.clone();
+                    let profile = match self.authenticator.lookup(&content).await {
+                        Ok(profile) => profile,
+                        Err(err) => return self.authentication_unavailable(&content, err).await
+                    };
+                    if let Some(profile) = profile {
+                        self.name = Some(content.clone());
 
-                        if password_req {
+                        if !profile.is_active(Utc::now()) {
+                            self.name = None;
+                            self.waiting_password = false;
+                            self = self.send_response(Response::new(ResponseCode::NotLoggedIn, "Account disabled or expired\r\n")).await?;
+                        } else if profile.anonymous {
+                            self.waiting_password = true;
+                            self = self.send_response(Response::new(ResponseCode::UserNameOkayNeedPassword, "Guest login ok, send your complete e-mail address as password\r\n")).await?;
+                        } else if profile.needs_password {
                             self.waiting_password = true;
-                            self = self.send_response(Response::new(ResponseCode::UserNameOkayNeedPassword, &format!("Provide password for {}\r\n", name.unwrap()))).await?;
+                            self = self.send_response(Response::new(ResponseCode::UserNameOkayNeedPassword, &format!("Provide password for {}\r\n", content))).await?;
+                        } else if self.login_guard.is_ip_banned(self.peer_ip) || self.login_guard.is_user_banned(&content) {
+                            return self.close(Response::new(ResponseCode::ServiceNotAvailable, "Too many failed logins, try again later\r\n")).await;
+                        } else if profile.totp_secret.is_some() {
+                            // the second factor of the users without a password is still required
+                            self.waiting_password = true;
+                            self.pending_profile = Some(Box::new(profile));
+                            self = self.send_response(Response::new(ResponseCode::NeedAccountForLogin, "Send your one-time code with ACCT\r\n")).await?;
                         } else {
-                            self.waiting_password = false;
-                            self = self.send_response(Response::new(ResponseCode::UserLoggedIn, &format!("Welcome {}!\r\n", name.unwrap()))).await?; // name == content
+                            self = self.login(profile).await?;
                         }
+                    } else {
+                        self = self.send_response(Response::new(ResponseCode::NotLoggedIn, "Unknown User!\r\n")).await?;
                     }
                 }
             },
             Command::NOOP => self = self.send_response(Response::new(ResponseCode::Ok, "No Operation\r\n")).await?,
+            Command::FEAT => self = self.feat().await?,
+            Command::OPTS(args) => self = self.opts(args).await?,
             Command::UNKNOWN(s) => self = self.send_response(Response::new(ResponseCode::UnknownCommand, &format!("\"{}\": [Command Not Implemented]\r\n",s))).await?,
             _ => {
                 // handling the Command when User is not logged in
@@ -175,75 +332,328 @@ impl Client {
         Ok(self)
     }
 
-    async fn handle_cwd(mut self, directory: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&directory);
-        let (new_client, dir) = self.complete_path(path);
+    /// Finishing the login of an authenticated user, setting up the root of the session
+    async fn login(mut self, mut profile: UserProfile) -> Result<Self> {
+        if !profile.is_active(Utc::now()) {
+            self.name = None;
+            self.waiting_password = false;
+            return self.send_response(Response::new(ResponseCode::NotLoggedIn, "Account disabled or expired\r\n")).await;
+        }
+
+        // answered like a wrong password, so the reply doesn't tell the address is the issue
+        if !profile.ip_filter.allows(self.peer_ip) {
+            eprintln!("Refusing the login of {} from {}", profile.username, self.peer_ip);
+            self.name = None;
+            self.waiting_password = false;
+            return self.login_failed(&profile.username).await;
+        }
 
-        self = new_client;
+        apply_groups(&mut profile, &self.ftp_config.groups);
 
-        if let Ok(dir) = dir {
-            let (new_client, new_path) = self.strip_prefix(dir);
-            self = new_client;
+        let (root, location) = match profile.storage.as_ref().and_then(new_storage) {
+            // users with their own storage are confined to it
+            Some(storage) => {
+                self.home = Some(PathBuf::from("/"));
+                (storage, format!("user:{}", profile.username))
+            },
+            None => {
+                self.home = match self.open_home(&profile).await {
+                    Ok(home) => home,
+                    Err(err) => {
+                        eprintln!("Unable to open the home directory of {}: {}", profile.username, err);
+                        self.name = None;
+                        self.waiting_password = false;
+                        return self.send_response(Response::new(ResponseCode::NotLoggedIn, "Home directory unavailable\r\n")).await;
+                    }
+                };
+                let home = self.home.clone().unwrap_or_default();
+                (self.storage_root.open(&home), format!("root:{}", home.display()))
+            }
+        };
+        let root: Arc<dyn StorageBackend> = match self.ftp_config.encryption {
+            Some(ref config) => match EncryptedStorage::open(root, config).await {
+                Ok(storage) => Arc::new(storage),
+                Err(err) => {
+                    eprintln!("Unable to read the encryption key {}: {}", config.key_file.display(), err);
+                    self.name = None;
+                    self.waiting_password = false;
+                    return self.send_response(Response::new(ResponseCode::NotLoggedIn, "Home directory unavailable\r\n")).await;
+                }
+            },
+            None => root
+        };
+        let root = self.protect(root, location, self.ftp_config.overwrite, profile.quota, &profile);
+        let mounts = self.open_mounts(&profile).await;
+        self.storage = Arc::new(MountedStorage::new(root, mounts));
+        self.waiting_password = false;
+        self.is_admin = profile.is_admin;
+        self.cwd = PathBuf::from("/");
+
+        let message = if profile.anonymous {
+            "Guest login ok, access restrictions apply\r\n".to_string()
+        } else {
+            format!("Welcome {}!\r\n", profile.username)
+        };
+        self.profile = Some(Box::new(profile));
+
+        self.send_response(Response::new(ResponseCode::UserLoggedIn, &message)).await
+    }
+
+    /// Verifying the password of the user, which can be followed by the one-time code for the users
+    /// with a TOTP secret. Returns the profile of the user, and whether its one-time code is still
+    /// expected with `ACCT`. Users logging in from an address they aren't allowed from are refused
+    /// like with a wrong password, before it is verified when the profile can be looked up.
+    async fn check_password(&self, name: &str, password: &str) -> Result<Option<(UserProfile, bool)>> {
+        let known = self.authenticator.lookup(name).await?;
+        if known.as_ref().is_some_and(|profile| !profile.ip_filter.allows(self.peer_ip)) {
+            eprintln!("Refusing the login of {} from {}", name, self.peer_ip);
+            return Ok(None);
+        }
+
+        // the code is only split off the password of the users known to have a TOTP secret, the
+        // whole password being verified too only when it is wrong without the code
+        let split = known.filter(|profile| profile.totp_secret.is_some()).and_then(|_| totp::split_code(password));
+        if let Some((password, code)) = split {
+            if let Some(profile) = self.authenticator.authenticate(name, password).await? {
+                let accepted = Self::verify_code(&profile, code) && profile.ip_filter.allows(self.peer_ip);
+                return Ok(accepted.then_some((profile, false)));
+            }
+        }
 
-            if let Ok(prefix) = new_path {
-                self.cwd = prefix.to_path_buf();
-                prefix_slash(&mut self.cwd);
+        let checked = self.authenticator.authenticate(name, password).await?.map(|profile| {
+            let needs_code = profile.totp_secret.is_some();
+            (profile, needs_code)
+        });
+        // the profiles of some authenticators are only known once the password is verified
+        Ok(checked.filter(|(profile, _)| profile.ip_filter.allows(self.peer_ip)))
+    }
+
+    fn verify_code(profile: &UserProfile, code: &str) -> bool {
+        match profile.totp_secret {
+            Some(ref secret) => totp::verify(secret, code.trim(), Utc::now().timestamp() as u64),
+            None => false
+        }
+    }
+
+    /// Answering a failed login after a delay growing with the failures, and closing the
+    /// connection after too many of them
+    async fn login_failed(mut self, name: &str) -> Result<Self> {
+        self.login_failures += 1;
+        let delay = self.login_guard.record_failure(self.peer_ip, name, self.login_failures);
+        tokio::time::sleep(delay).await;
+
+        self = self.send_response(Response::new(ResponseCode::NotLoggedIn, "Invalid Password\r\n")).await?;
+
+        let max_failures = self.login_guard.limits().max_failures_per_connection;
+        if max_failures > 0 && self.login_failures >= max_failures {
+            self = self.close(Response::new(ResponseCode::ServiceNotAvailable, "Too many failed logins, closing connection\r\n")).await?;
+        }
+        Ok(self)
+    }
+
+    /// Closing the connection when the authenticator fails, e.g. an unreadable htpasswd file or
+    /// an authentication command timing out
+    async fn authentication_unavailable(mut self, name: &str, err: FtpError) -> Result<Self> {
+        eprintln!("Unable to authenticate {}: {}", name, err);
+        self.name = None;
+        self.waiting_password = false;
+        self.close(Response::new(ResponseCode::ServiceNotAvailable, "Authentication service unavailable, closing connection\r\n")).await
+    }
+
+    /// Resolving and creating the home directory the session is confined to, in the storage root.
+    /// Admins and users without a home directory share the whole root.
+    async fn open_home(&self, profile: &UserProfile) -> io::Result<Option<PathBuf>> {
+        let template = match profile.home {
+            Some(ref home) => PathBuf::from(home),
+            None if profile.is_admin => return Ok(None),
+            None => match self.ftp_config.default_home {
+                Some(ref home) => PathBuf::from(home),
+                None => return Ok(None)
+            }
+        };
+
+        let base = match self.ftp_config.home_base {
+            Some(ref base) if !profile.anonymous => base.clone(),
+            _ => PathBuf::new()
+        };
+
+        let home = resolve_home(&template, &profile.username, &base)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::PermissionDenied))?;
+        self.storage_root.mkdir(&home).await?;
+        Ok(Some(home))
+    }
+
+    /// Resolving the folders mounted for everyone and for the user, directories or archives of the
+    /// storage root unless they have their own storage. Mounts with a missing source are skipped.
+    async fn open_mounts(&self, profile: &UserProfile) -> Vec<MountPoint> {
+        let mut mounts = vec![];
+        for (index, mount) in self.ftp_config.mounts.iter().chain(&profile.mounts).enumerate() {
+            let path = normalize_path(&mount.path);
+            // the mounts sharing a storage share its trash, the ones of the storage root by source
+            let location = match mount.storage {
+                None => format!("root:{}", normalize_path(&mount.source).display()),
+                Some(_) if index < self.ftp_config.mounts.len() => format!("mount:{}", path.display()),
+                Some(_) => format!("user:{}:{}", profile.username, path.display())
+            };
+            let storage = match mount.storage.as_ref().and_then(new_storage) {
+                _ if path == Path::new("/") => None,
+                Some(storage) => Some(storage),
+                None => match self.storage_root.metadata(&mount.source).await {
+                    Ok(metadata) if metadata.is_dir => Some(self.storage_root.open(&mount.source)),
+                    Ok(_) => self.open_archive(&mount.source).await,
+                    Err(_) => None
+                }
+            };
+            let storage = match (storage, &mount.encryption) {
+                (Some(storage), Some(config)) => match EncryptedStorage::open(storage, config).await {
+                    Ok(storage) => Some(Arc::new(storage) as Arc<dyn StorageBackend>),
+                    Err(err) => {
+                        eprintln!("Unable to read the encryption key {}: {}", config.key_file.display(), err);
+                        None
+                    }
+                },
+                (storage, _) => storage
+            };
+            let storage: Arc<dyn StorageBackend> = match storage {
+                Some(storage) if mount.read_only => Arc::new(ReadOnlyStorage::new(storage)),
+                Some(storage) => self.protect(storage, location, mount.overwrite.unwrap_or(self.ftp_config.overwrite), mount.quota, profile),
+                None => {
+                    eprintln!("Skipping mount of {} at {}: invalid source", mount.source.display(), mount.path.display());
+                    continue;
+                }
+            };
+            mounts.push(MountPoint { path, storage });
+        }
+        mounts
+    }
+
+    /// Checking if the uploads of a user are written atomically, which they always are when a quota
+    /// applies to them so that the uploads refused for going over it don't truncate a file
+    fn atomic_uploads(&self, profile: &UserProfile) -> bool {
+        self.ftp_config.atomic_uploads || profile.quota.is_some()
+            || self.ftp_config.mounts.iter().chain(&profile.mounts).any(|mount| mount.quota.is_some())
+    }
+
+    /// Applying the trash, the overwrite policy, the atomic uploads and the quota of a writable part
+    /// of the session's tree, the location identifying the storage across the sessions
+    fn protect(&self, storage: Arc<dyn StorageBackend>, location: String, overwrite: OverwritePolicy, quota: Option<Quota>, profile: &UserProfile) -> Arc<dyn StorageBackend> {
+        let storage: Arc<dyn StorageBackend> = match self.ftp_config.trash {
+            Some(config) => {
+                let trash = Arc::new(TrashStorage::new(storage, config, &profile.username));
+                self.trashes.register(location.clone(), trash.clone());
+                trash
+            },
+            None => storage
+        };
+        let storage: Arc<dyn StorageBackend> = match overwrite {
+            OverwritePolicy::Allow => storage,
+            overwrite => Arc::new(VersioningStorage::new(storage, overwrite))
+        };
+        // the quota sees the destination of the uploads rather than their temporary files
+        let storage: Arc<dyn StorageBackend> = match self.atomic_uploads(profile) {
+            true => Arc::new(AtomicStorage::new(storage)),
+            false => storage
+        };
+        match quota {
+            Some(quota) => Arc::new(QuotaStorage::new(storage, quota, self.usages.get(&location))),
+            None => storage
+        }
+    }
+
+    /// Opening a zip or tar archive of the local storage root as a read-only storage
+    async fn open_archive(&self, source: &Path) -> Option<Arc<dyn StorageBackend>> {
+        let archive = self.storage_root.local_path(source)?;
+        ArchiveKind::from_path(&archive)?;
+        match ArchiveStorage::open(archive).await {
+            Ok(storage) => Some(Arc::new(storage)),
+            Err(err) => {
+                eprintln!("Unable to open the archive {}: {}", source.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Getting the virtual path of a command argument, relative to the working directory
+    fn virtual_path(&self, path: &Path) -> PathBuf {
+        normalize_path(&self.cwd.join(path))
+    }
+
+    /// Checking if the virtual path is the config file of the server, hidden from everyone but the admin
+    fn is_hidden(&self, path: &Path) -> bool {
+        !self.is_admin && self.home.is_none() && path == Path::new("/").join(CONFIG_FILE)
+    }
+
+    /// Checking if the user has the permission on the virtual path
+    fn allowed(&self, permission: Permission, path: &Path) -> bool {
+        match self.profile {
+            Some(ref profile) => {
+                let permissions = profile.permissions.clone().unwrap_or_default();
+                permissions_for(&permissions, &profile.path_permissions, path).allows(permission)
+            },
+            None => false
+        }
+    }
+
+    /// Refusing the command for lack of permissions, closing any opened data connection
+    async fn deny(mut self) -> Result<Self> {
+        self.close_data_connection();
+        self.send_response(Response::new(ResponseCode::FileNotFound, "Permission denied\r\n")).await
+    }
+
+    async fn handle_cwd(mut self, directory: PathBuf) -> Result<Self> {
+        let path = self.virtual_path(&directory);
+
+        match self.storage.metadata(&path).await {
+            Ok(metadata) if metadata.is_dir => {
+                self.cwd = path;
                 let resp = Response::new(
                     ResponseCode::RequestedFileActionOkay,
                     &format!("Directory changed to \"{}\"\r\n", directory.display())
                 );
 
-                self = self.send_response(resp).await?;
-
-                Ok(self)
-            } else {
-                self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await?;
-                Ok(self)
-            }
-        } else {
-            self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await?;
-            Ok(self)
+                self.send_response(resp).await
+            },
+            _ => self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await
         }
-
     }
 
     /// Handling the List command
     async fn list(mut self, mut args: Option<String>) -> Result<Self> {
-        // , path_buf: Option<PathBuf>
         if let command = args.take().unwrap() {
             if command.starts_with('-') || command.is_empty() {
                 if String::from("-al").eq(&command) || command.is_empty() {
                     // IMPLEMENTATION FOR -al
                     if self.data_writer.is_some() {
-                        println!("<><><>DATA is some");
-                        // let path = self.cwd.join(get_current_dir());
                         let path = self.cwd.clone();
-                        let directory = PathBuf::from(&path);
-                        let (new_client, complete_path) = self.complete_path(directory);
-                        self = new_client;
-                        if let Ok(path) = complete_path {
+                        if !self.allowed(Permission::List, &path) {
+                            return self.deny().await;
+                        }
+
+                        if let Ok(metadata) = self.storage.metadata(&path).await {
                             self = self.send_response(
                                 Response::new(ResponseCode::DataConnectionAlreadyOpen, "Starting to list directories\r\n")
                             ).await?;
 
                             let mut out = vec![];
 
-                            if path.is_dir() {
-                                if let Ok(mut dir_reader) = read_dir(path).await{
-                                    while let Some(entry) = dir_reader.next_entry().await? {
-                                        if self.is_admin || entry.path() != self.server_root_dir.join(CONFIG_FILE) {
-                                            add_file_info(entry.path(), &mut out).await;
+                            if metadata.is_dir {
+                                if let Ok(entries) = self.storage.list(&path).await {
+                                    for entry in entries {
+                                        if !self.is_hidden(&path.join(&entry.name)) {
+                                            add_file_info(&entry.name, &entry.metadata, &mut out);
                                         }
                                     }
-                                    // self = self.send_response(Response::new(ResponseCode::ClosingDataConnection, "Directory send OK\r\n")).await?;
                                 } else {
                                     self = self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "No such file or directory\r\n")).await?;
                                     return Ok(self);
                                 }
-                            } else if self.is_admin || path != self.server_root_dir.join(CONFIG_FILE) {
-                                add_file_info(path, &mut out).await;
+                            } else if !self.is_hidden(&path) {
+                                let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                                add_file_info(&name, &metadata, &mut out);
+                            }
+                            if let Err(err) = self.send_data(out).await {
+                                return self.transfer_aborted(err).await;
                             }
-                            self = self.send_data(out).await?;
                             println!("-> DONE TRAVERSING DIRECTORIES");
                         } else {
                             self = self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "No such file or directory1\r\n")).await?;
@@ -259,6 +669,9 @@ impl Client {
         }
 
         if self.data_writer.is_some() {
+            if let Err(err) = self.finish_data().await {
+                return self.transfer_aborted(err).await;
+            }
             self.close_data_connection();
             self = self.send_response(Response::new(ResponseCode::ClosingDataConnection, "Directories Transfer done\r\n")).await?;
         }
@@ -304,37 +717,44 @@ impl Client {
     async fn retr(mut self, path: PathBuf) -> Result<Self> {
         // checking for multiple data connections
         if self.data_writer.is_some() {
-            let path = self.cwd.join(path);
-            let (new_client, complete_path) = self.complete_path(path.clone());
-            self = new_client;
-
-            if let Ok(path) = complete_path {
-                if path.is_file() && (self.is_admin || path != self.server_root_dir.join(CONFIG_FILE)) {
-                    self = self.send_response(Response::new(ResponseCode::DataConnectionAlreadyOpen, "Starting to send the file\r\n")).await?;
-
-                    let mut file = File::open(path).await?;
-
-                    // reading the file all at once, but works for small files
-                    // let mut outbound = vec![];
-                    // file.read_to_end(&mut outbound).await?;
-
+            let path = self.virtual_path(&path);
+            if !self.allowed(Permission::Read, &path) {
+                return self.deny().await;
+            }
 
-                    // reading File chunk by chunk (8KB chunk) and sending via buffer
-                    let mut buffer = [0; 8192];
-                    loop {
-                        let bytes_read = file.read(&mut buffer).await?;
-                        if bytes_read == 0 {
-                            break;
+            let is_file = matches!(self.storage.metadata(&path).await, Ok(metadata) if !metadata.is_dir);
+            if is_file && !self.is_hidden(&path) {
+                let mut file = match self.storage.open_read(&path, 0).await {
+                    Ok(file) => file,
+                    Err(err) => {
+                        eprintln!("Unable to read {}: {}", path.display(), err);
+                        self.close_data_connection();
+                        return self.send_response(Response::new(ResponseCode::FileNotFound, "Unable to read the file\r\n")).await;
+                    }
+                };
+                self = self.send_response(Response::new(ResponseCode::DataConnectionAlreadyOpen, "Starting to send the file\r\n")).await?;
+
+                // reading File chunk by chunk (8KB chunk) and sending via buffer
+                let mut buffer = [0; 8192];
+                loop {
+                    let bytes_read = match file.read(&mut buffer).await {
+                        Ok(bytes_read) => bytes_read,
+                        Err(err) => {
+                            eprintln!("Download of {} failed: {}", path.display(), err);
+                            self.close_data_connection();
+                            return self.send_response(Response::new(ResponseCode::LocalErrorInProcessing, "Unable to read the file, transfer aborted\r\n")).await;
                         }
-                        self = self.send_data(buffer[..bytes_read].to_vec()).await?;
+                    };
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    if let Err(err) = self.send_data(buffer[..bytes_read].to_vec()).await {
+                        return self.transfer_aborted(err).await;
                     }
-                    println!("\t\tTransfer Done ==>");
-                } else {
-                    let message = format!("\"{}\" doesnt exist", path.to_str().ok_or_else(|| FtpError::Msg("No Path\r\n".to_string()))?);
-                    self = self.send_response(Response::new(ResponseCode::LocalErrorInProcessing, &message)).await?;
                 }
+                println!("\t\tTransfer Done ==>");
             } else {
-                let message = format!("\"{}\" doesnt exist", path.to_str().ok_or_else(|| FtpError::Msg("No Path\r\n".to_string()))?);
+                let message = format!("\"{}\" doesnt exist\r\n", path.display());
                 self = self.send_response(Response::new(ResponseCode::LocalErrorInProcessing, &message)).await?;
             }
         } else {
@@ -342,55 +762,113 @@ impl Client {
         }
 
         if self.data_writer.is_some() {
+            if let Err(err) = self.finish_data().await {
+                return self.transfer_aborted(err).await;
+            }
             self.close_data_connection();
             self = self.send_response(Response::new(ResponseCode::ClosingDataConnection, "Data connection closed, Transfer Done\r\n")).await?;
         }
         Ok(self)
     }
 
-    async fn stor(mut self, path: PathBuf) -> Result<Self> {
+    /// Storing an uploaded file, under a name which isn't taken for `STOU`
+    async fn stor(mut self, path: PathBuf, unique: bool) -> Result<Self> {
         println!("-> STOR: {:?}", &path);
         // handle permissions  for the file creation in the server
         if self.data_reader.is_some() {
-            if invalid_path(&path)  || (!self.is_admin && path == self.server_root_dir.join(CONFIG_FILE)){
+            if invalid_path(&path) {
                 let error: io::Error = io::ErrorKind::PermissionDenied.into();
                 return Err(error.into());
             }
 
-            // if path.is_file() {
-                let cwd = self.cwd.clone();
-                let (new_client, complete_dir_path) = self.complete_path(cwd.clone());
-                self = new_client;
+            // checked before the filename policy lists the directory, so names can't be probed
+            if !self.allowed(Permission::Write, &self.virtual_path(&path)) {
+                return self.deny().await;
+            }
 
-                if let Ok(complete_dir_path) = complete_dir_path {
+            let file_path = match self.check_filename(&self.virtual_path(&path)).await {
+                Ok(file_path) => file_path,
+                Err(reason) => {
+                    self.close_data_connection();
+                    return self.send_response(Response::new(ResponseCode::FileNameNotAllowed, &format!("{}\r\n", reason))).await;
+                }
+            };
+            let mut file_path = if unique { self.unique_path(&file_path).await } else { file_path };
 
-                    let append_path = if let Ok(path) = path.strip_prefix("/") {
-                        path
-                    } else {
-                        return Err(FtpError::Msg("Invalid Path Provided".to_string()));
-                    };
-                    let file_path = complete_dir_path.join(append_path);
-                    // file_path.push(path.to_str());
-
-                    // println!("-> SERVER ROOT: {:?}", &self.server_root_dir);
-                    println!("-> STOR PATH: {:?}", &file_path);
-                    self = self.send_response(Response::new(ResponseCode::DataConnectionAlreadyOpen, "Starting to Store the file\r\n")).await?;
-                    let (new_client, file_metadata) = self.receive_data(file_path).await?;
-                    self = new_client;
-                    let permissions = get_permissions(&complete_dir_path.metadata()?);
-                    println!("-- ROOT PERMISSIONS: {:?}", &permissions);
-                    println!("\t\tTransfer Done <==");
-                } else {
-                    return Err(FtpError::Msg("No Path Provided".to_string()));
-                };
+            // overwriting a file also requires the permission to delete it
+            let exists = self.storage.metadata(&file_path).await.is_ok();
+            if self.is_hidden(&file_path) || !self.allowed(Permission::Write, &file_path) || (exists && !self.allowed(Permission::Delete, &file_path)) {
+                return self.deny().await;
+            }
 
+            let policies: Vec<UploadPolicy> = self.ftp_config.upload_policies.iter().filter(|policy| policy.applies_to(&file_path)).cloned().collect();
+            let name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if let Some(violation) = policies.iter().find_map(|policy| policy.check_name(&name).err()) {
                 self.close_data_connection();
+                return self.send_response(Self::upload_error(&violation.into())).await;
+            }
 
-                self = self.send_response(Response::new(ResponseCode::ClosingDataConnection, "Data connection closed, Transfer Done\r\n")).await?;
-            // } else {
-            //     self = self.send_response(Response::new(ResponseCode::FileNotFound, "Trying to insert a directory\r\n")).await?;
-            // }
+            println!("-> STOR PATH: {:?}", &file_path);
+            // the name picked by `STOU` is taken as the file is created, another one being picked
+            // when a concurrent upload took it first
+            let mut attempts = 0;
+            let writer = loop {
+                let writer = match self.free_space(&file_path).await {
+                    Ok(free_space) => self.storage.open_write(&file_path, false, unique).await.map(|writer| Self::limit(writer, free_space)),
+                    Err(err) => Err(err)
+                };
+                match writer {
+                    Err(err) if unique && err.kind() == io::ErrorKind::AlreadyExists && attempts < 3 => {
+                        attempts += 1;
+                        file_path = self.unique_path(&file_path).await;
+                    },
+                    writer => break writer
+                }
+            };
+            let name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let writer = match writer {
+                Ok(writer) => writer,
+                Err(err) => {
+                    eprintln!("Unable to store {}: {}", file_path.display(), err);
+                    self.close_data_connection();
+                    return self.send_response(Self::upload_error(&err)).await;
+                }
+            };
+            let writer = policies.iter().fold(writer, |writer, policy| policy.writer(writer));
+            let writer = match self.ftp_config.upload_checksums {
+                Some(ref checksums) => checksums.writer(writer, self.storage.clone(), &file_path).await,
+                None => writer
+            };
+
+            let message = if unique {
+                format!("FILE: {}\r\n", name)
+            } else {
+                "Starting to Store the file\r\n".to_string()
+            };
+            self = self.send_response(Response::new(ResponseCode::DataConnectionAlreadyOpen, &message)).await?;
+            let (new_client, result) = self.receive_data(writer).await?;
+            self = new_client;
+            println!("\t\tTransfer Done <==");
+
+            self.close_data_connection();
 
+            self = match result {
+                Ok(_) if unique || file_path != self.virtual_path(&path) => {
+                    let message = format!("Data connection closed, Transfer Done, stored as {}\r\n", name);
+                    self.send_response(Response::new(ResponseCode::ClosingDataConnection, &message)).await?
+                },
+                Ok(_) => self.send_response(Response::new(ResponseCode::ClosingDataConnection, "Data connection closed, Transfer Done\r\n")).await?,
+                Err(err) => {
+                    eprintln!("Upload of {} failed: {}", file_path.display(), err);
+                    // partial files breaking the limits would stay around
+                    let refused = matches!(err.kind(), io::ErrorKind::QuotaExceeded | io::ErrorKind::StorageFull)
+                        || policy_violation(&err).is_some() || is_checksum_mismatch(&err) || is_invalid_compressed_data(&err);
+                    if refused && !self.profile.as_deref().is_some_and(|profile| self.atomic_uploads(profile)) {
+                        self.storage.remove(&file_path).await.ok();
+                    }
+                    self.send_response(Self::upload_error(&err)).await?
+                }
+            };
         } else {
             self = self.send_response(Response::new(ResponseCode::ConnectionClosed, "No opened data connection\r\n")).await?;
         }
@@ -399,36 +877,27 @@ impl Client {
     }
 
     async fn mkd(mut self, path: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&path);
+        if !self.allowed(Permission::Mkdir, &self.virtual_path(&path)) {
+            return self.deny().await;
+        }
+        let path = match self.check_filename(&self.virtual_path(&path)).await {
+            Ok(path) => path,
+            Err(reason) => return self.send_response(Response::new(ResponseCode::FileNameNotAllowed, &format!("{}\r\n", reason))).await
+        };
         let parent = self.get_parent(path.clone());
 
-        if let Some(parent) = parent {
-            let parent = parent.to_path_buf();
-
-            let (new_client, complete_path) = self.complete_path(parent);
-            self = new_client;
-
-            if let Ok(mut dir) = complete_path {
-                if dir.is_dir() {
-                    let filename = get_filename(path);
-
-                    if let Some(folder) = filename {
-                        dir.push(folder);
-                        if create_dir_all(&dir).await.is_ok() {
-                            let mut permissions = tokio::fs::metadata(&dir).await?.permissions();
-                            permissions.set_mode(0o755);
+        if let (Some(parent), Some(_)) = (parent, get_filename(path.clone())) {
+            if matches!(self.storage.metadata(&parent).await, Ok(metadata) if metadata.is_dir) {
+                if !self.allowed(Permission::Mkdir, &path) {
+                    return self.deny().await;
+                }
 
-                            self = self.send_response(Response::new(ResponseCode::PATHNAMECreated, "Directory created\r\n")).await?;
-                            return Ok(self);
-                        } else {
-                            self = self.send_response(Response::new(ResponseCode::FileNotFound, "Unable to create Folder 1X\r\n")).await?;
-                        }
-                    } else {
-                        self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory 2X\r\n")).await?;
-                    }
+                if self.storage.mkdir(&path).await.is_ok() {
+                    self = self.send_response(Response::new(ResponseCode::PATHNAMECreated, "Directory created\r\n")).await?;
                 } else {
-                    self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory 3X\r\n")).await?;
+                    self = self.send_response(Response::new(ResponseCode::FileNotFound, "Unable to create Folder 1X\r\n")).await?;
                 }
+                return Ok(self);
             }
         }
 
@@ -436,43 +905,48 @@ impl Client {
 
         Ok(self)
     }
+
     async fn rmd(mut self, item_path: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(&item_path);
-        let (new_client, complete_path) = self.complete_path(path);
-        self = new_client;
-
-        if let Ok(item) = complete_path {
-            if item.is_dir() {
-                if remove_dir_all(&item).await.is_ok() {
-                    self = self.send_response(Response::new(ResponseCode::RequestedFileActionOkay, "Folder Removed successfully\r\n")).await?;
-                    return Ok(self);
-                } else {
-                    self = self.send_response(Response::new(ResponseCode::FileNotFound, "Couldn't Remove Folder\r\n")).await?;
-                }
+        let path = self.virtual_path(&item_path);
+
+        if let Ok(metadata) = self.storage.metadata(&path).await {
+            let permission = if metadata.is_dir { Permission::Rmdir } else { Permission::Delete };
+            if path == Path::new("/") || !self.allowed(permission, &path) {
+                return self.deny().await;
             }
 
-            if item.is_file() {
-                if remove_file(&item).await.is_ok() {
-                    self = self.send_response(Response::new(ResponseCode::RequestedFileActionOkay, "File Removed successfully\r\n")).await?;
-                    return Ok(self);
-                } else {
-                    self = self.send_response(Response::new(ResponseCode::FileNotFound, "Couldn't Remove File\r\n")).await?;
+            let kind = if metadata.is_dir { "Folder" } else { "File" };
+            match self.storage.delete(&path).await {
+                Ok(()) => {
+                    self = self.send_response(Response::new(ResponseCode::RequestedFileActionOkay, &format!("{} Removed successfully\r\n", kind))).await?;
+                },
+                // mount points can't be removed
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return self.deny().await,
+                Err(_) => {
+                    self = self.send_response(Response::new(ResponseCode::FileNotFound, &format!("Couldn't Remove {}\r\n", kind))).await?;
                 }
             }
+        } else {
+            self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await?;
         }
         Ok(self)
     }
 
     async fn get_size(mut self, path: PathBuf) -> Result<Self> {
-        let path = self.cwd.join(path);
-        let (new_client, complete_path) = self.complete_path(path);
-        self = new_client;
-
-        if let Ok(path) = complete_path {
-            let metadata = path.metadata()?;
-            if path.is_file() {
-                let (_timestamp, file_size) = get_file_info(&metadata);
-                self = self.send_response(Response::new(ResponseCode::FileStatus, &format!("{}\r\n", file_size))).await?;
+        let path = self.virtual_path(&path);
+
+        let metadata = match self.storage.metadata(&path).await {
+            Ok(_) if self.is_hidden(&path) => None,
+            metadata => metadata.ok()
+        };
+
+        if let Some(metadata) = metadata {
+            if !self.allowed(Permission::Read, &path) {
+                return self.deny().await;
+            }
+
+            if !metadata.is_dir {
+                self = self.send_response(Response::new(ResponseCode::FileStatus, &format!("{}\r\n", metadata.size))).await?;
             } else {
                 self = self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory 2X\r\n")).await?;
             }
@@ -483,43 +957,173 @@ impl Client {
         Ok(self)
     }
 
-    fn get_parent(&self, path: PathBuf) -> Option<PathBuf> {
-        path.parent().map(|p| p.to_path_buf())
-    }
+    /// Setting the modification time of a file, given in UTC e.g. `MFMT 20240131093000 report.csv`
+    async fn mfmt(self, args: String) -> Result<Self> {
+        let (time, file) = get_first_word_and_rest(args.trim());
+        let modified = match time.filter(|time| time.len() == 14 && time.bytes().all(|b| b.is_ascii_digit())) {
+            Some(time) => NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S").ok(),
+            None => None
+        };
+        let (modified, file) = match (modified, file.map(str::trim).filter(|file| !file.is_empty())) {
+            (Some(modified), Some(file)) => (modified, file),
+            _ => return self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Invalid time or file name\r\n")).await
+        };
 
-    fn complete_path(self, path: PathBuf) -> (Self, result::Result<PathBuf, io::Error>) {
-        let directory = self.server_root_dir.join( if path.has_root() {
-            path.iter().skip(1).collect()
-        } else {
-            path
-        });
-        let dir = directory.canonicalize();
+        let path = self.virtual_path(Path::new(file));
+        if !self.allowed(Permission::Write, &path) {
+            return self.deny().await;
+        }
+        if self.is_hidden(&path) || self.storage.metadata(&path).await.is_err() {
+            return self.send_response(Response::new(ResponseCode::FileNotFound, "No such file or directory\r\n")).await;
+        }
 
-        if let Ok(ref dir) = dir {
-            if !dir.starts_with(&self.server_root_dir) {
-                return (self, Err(io::ErrorKind::PermissionDenied.into()))
+        let reply = match self.storage.set_mtime(&path, modified.and_utc().into()).await {
+            Ok(()) => Response::new(ResponseCode::FileStatus, &format!("Modify={}; {}\r\n", modified.format("%Y%m%d%H%M%S"), file)),
+            Err(err) => {
+                eprintln!("Unable to set the modification time of {}: {}", path.display(), err);
+                Response::new(ResponseCode::FileNotFound, "Unable to set the modification time\r\n")
             }
+        };
+        self.send_response(reply).await
+    }
+
+    /// Handling the site specific commands, e.g. `SITE RESTORE /alice/.trash/<item>` for the admin
+    /// to restore a deleted item
+    async fn site(mut self, args: String) -> Result<Self> {
+        let (command, arg) = get_first_word_and_rest(args.trim());
+        match command.unwrap_or("").to_uppercase().as_str() {
+            "RESTORE" if !self.is_admin => self.deny().await,
+            "RESTORE" => {
+                let path = self.virtual_path(Path::new(arg.unwrap_or("").trim()));
+                let reply = match trash::restore(self.storage.as_ref(), &path).await {
+                    Ok(restored) => Response::new(ResponseCode::RequestedFileActionOkay, &format!("Restored to {}\r\n", restored.display())),
+                    Err(err) if err.kind() == io::ErrorKind::InvalidInput => Response::new(ResponseCode::InvalidParameterOrArgument, "Not an item of a trash directory\r\n"),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Response::new(ResponseCode::FileNotFound, "Original path already exists\r\n"),
+                    Err(_) => Response::new(ResponseCode::FileNotFound, "No such item in the trash\r\n")
+                };
+                self = self.send_response(reply).await?;
+                Ok(self)
+            },
+            _ => self.send_response(Response::new(ResponseCode::CommandNotImplementedForThatParameter, "Unknown SITE command\r\n")).await
         }
+    }
 
-        (self,dir)
+    /// Listing the extensions of the server
+    async fn feat(self) -> Result<Self> {
+        let algorithms: Vec<String> = HashAlgorithm::ALL.iter().map(|&algorithm| {
+            if algorithm == self.hash_algorithm { format!("{}*", algorithm.name()) } else { algorithm.name().to_string() }
+        }).collect();
+        let features = vec![format!("HASH {}", algorithms.join(";")), "MFMT".to_string(), "MODE Z".to_string(), "RANG STREAM".to_string(), "SIZE".to_string()];
+        self.send_response(Response::multiline(ResponseCode::SystemStatus, "Features:\r\n", features)).await
     }
 
-    fn complete_file_path(self, path:PathBuf) -> (Self, result::Result<PathBuf, io::Error>) {
-        let file_path = self.server_root_dir.join(path);
-        let file = file_path.canonicalize();
-        if let Ok(ref file) = file {
-            if !file.starts_with(&self.server_root_dir) {
-                return (self, Err(io::ErrorKind::PermissionDenied.into()))
+    /// Setting the options of a command, the algorithm of `HASH` with `OPTS HASH <algorithm>`
+    /// and the compression level of `MODE Z` with `OPTS MODE Z LEVEL <level>`
+    async fn opts(mut self, args: String) -> Result<Self> {
+        let (command, arg) = get_first_word_and_rest(args.trim());
+        if command.unwrap_or("").eq_ignore_ascii_case("MODE") {
+            let words: Vec<String> = arg.unwrap_or("").split_whitespace().map(str::to_uppercase).collect();
+            return match words.as_slice() {
+                [mode, option, level] if mode == "Z" && option == "LEVEL" => match level.parse::<u32>() {
+                    Ok(level) if level <= 9 => {
+                        self.compression_level = level;
+                        self.send_response(Response::new(ResponseCode::Ok, &format!("MODE Z LEVEL set to {}\r\n", level))).await
+                    },
+                    _ => self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Invalid compression level\r\n")).await
+                },
+                _ => self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Option not supported\r\n")).await
+            };
+        }
+        if !command.unwrap_or("").eq_ignore_ascii_case("HASH") {
+            return self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Option not supported\r\n")).await;
+        }
+        match arg.map(str::trim).filter(|arg| !arg.is_empty()) {
+            None => (),
+            Some(name) => match HashAlgorithm::from_name(name) {
+                Some(algorithm) => self.hash_algorithm = algorithm,
+                None => return self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Unknown algorithm\r\n")).await
             }
         }
-        (self, file)
+        let message = format!("{}\r\n", self.hash_algorithm.name());
+        self.send_response(Response::new(ResponseCode::Ok, &message)).await
     }
 
-    fn strip_prefix(self, dir: PathBuf) -> (Self, result::Result<PathBuf, StripPrefixError>) {
-        let res = dir.strip_prefix(&self.server_root_dir).map(|p| p.to_path_buf());
-        (self, res)
+    /// Setting the byte range of the next `HASH`, `RANG 1 0` resetting it
+    async fn rang(mut self, args: String) -> Result<Self> {
+        let numbers: Vec<u64> = args.split_whitespace().filter_map(|number| number.parse().ok()).collect();
+        match numbers[..] {
+            [1, 0] => {
+                self.range = None;
+                self.send_response(Response::new(ResponseCode::RequestedFileActionPendingFurtherInformation, "Byte range reset\r\n")).await
+            },
+            [start, end] if start <= end => {
+                self.range = Some((start, end));
+                let message = format!("Restarting at {}. Ending byte range at {}\r\n", start, end);
+                self.send_response(Response::new(ResponseCode::RequestedFileActionPendingFurtherInformation, &message)).await
+            },
+            _ => self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Invalid byte range\r\n")).await
+        }
     }
 
+    /// Getting the size of a file the user can read, to be hashed
+    async fn hashed_size(&self, path: &Path) -> Option<u64> {
+        match self.storage.metadata(path).await {
+            Ok(metadata) if !metadata.is_dir && !self.is_hidden(path) => Some(metadata.size),
+            _ => None
+        }
+    }
+
+    /// Hashing a file with the algorithm of the session, over the range set by `RANG` if any
+    async fn hash(mut self, file: PathBuf) -> Result<Self> {
+        let path = self.virtual_path(&file);
+        let range = self.range.take();
+        if !self.allowed(Permission::Read, &path) {
+            return self.deny().await;
+        }
+        let size = match self.hashed_size(&path).await {
+            Some(size) => size,
+            None => return self.send_response(Response::new(ResponseCode::FileNotFound, "No such file\r\n")).await
+        };
+
+        let (start, end) = match range {
+            // the range is only known to fit the file once it's hashed
+            Some((_, end)) if end >= size && size > 0 => {
+                return self.send_response(Response::new(ResponseCode::InvalidParameterOrArgument, "Byte range past the end of the file\r\n")).await;
+            },
+            Some((start, end)) => (start, end.saturating_add(1).min(size)),
+            None => (0, size)
+        };
+        let reply = match checksum(self.storage.as_ref(), &path, self.hash_algorithm, start, Some(end)).await {
+            Ok(hash) => {
+                let message = format!("{} {}-{} {} {}\r\n", self.hash_algorithm.name(), start, end.saturating_sub(1).max(start), hash, file.display());
+                Response::new(ResponseCode::FileStatus, &message)
+            },
+            Err(_) => Response::new(ResponseCode::LocalErrorInProcessing, "Unable to read the file\r\n")
+        };
+        self.send_response(reply).await
+    }
+
+    /// Hashing a file with the algorithm of an `XCRC`-like command, over the range of its arguments
+    async fn x_checksum(self, algorithm: HashAlgorithm, args: String) -> Result<Self> {
+        let (file, start, end) = parse_checksum_args(&args);
+        let path = self.virtual_path(Path::new(&file));
+        if !self.allowed(Permission::Read, &path) {
+            return self.deny().await;
+        }
+        if self.hashed_size(&path).await.is_none() {
+            return self.send_response(Response::new(ResponseCode::FileNotFound, "No such file\r\n")).await;
+        }
+
+        let reply = match checksum(self.storage.as_ref(), &path, algorithm, start, end.filter(|&end| end > 0)).await {
+            Ok(hash) => Response::new(ResponseCode::RequestedFileActionOkay, &format!("{}\r\n", hash)),
+            Err(_) => Response::new(ResponseCode::LocalErrorInProcessing, "Unable to read the file\r\n")
+        };
+        self.send_response(reply).await
+    }
+
+    fn get_parent(&self, path: PathBuf) -> Option<PathBuf> {
+        path.parent().map(|p| p.to_path_buf())
+    }
 
     async fn send_response(mut self, resp: Response) -> Result<Self> {
         let resp_string = resp.to_string();
@@ -528,59 +1132,196 @@ impl Client {
         Ok(self)
     }
 
-    async fn send_data(mut self, data: Vec<u8>) -> Result<Self> {
-        if let Some(mut writer) = self.data_writer {
+    async fn send_data(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let data = if self.deflate {
+            let level = self.compression_level;
+            let encoder = self.encoder.get_or_insert_with(|| ZlibEncoder::new(vec![], Compression::new(level)));
+            encoder.write_all(&data)?;
+            mem::take(encoder.get_mut())
+        } else {
+            data
+        };
+        if let Some(ref mut writer) = self.data_writer {
             writer.write_all(&data).await?;
-            self.data_writer = Some(writer)
         }
-        Ok(self)
+        Ok(())
     }
 
-    async fn receive_data(mut self, destination: PathBuf) -> Result<(Self, u64)> {
-        if self.data_reader.is_some() {
-            // let mut file_data = vec![];
-            let mut dest_file = File::create(destination).await?;
-            let mut reader = self.data_reader.take().ok_or_else(|| FtpError::Msg("No data reader\r\n".to_string()))?;
+    /// Ending the data sent in `MODE Z` with the rest of its compressed stream
+    async fn finish_data(&mut self) -> io::Result<()> {
+        if self.deflate {
+            let encoder = self.encoder.take().unwrap_or_else(|| ZlibEncoder::new(vec![], Compression::new(self.compression_level)));
+            let data = encoder.finish()?;
+            if let Some(ref mut writer) = self.data_writer {
+                writer.write_all(&data).await?;
+            }
+        }
+        Ok(())
+    }
 
+    /// Answering a download interrupted by the client closing the data connection
+    async fn transfer_aborted(mut self, err: io::Error) -> Result<Self> {
+        eprintln!("Data connection closed during the transfer: {}", err);
+        self.close_data_connection();
+        self.send_response(Response::new(ResponseCode::ConnectionClosed, "Connection closed, transfer aborted\r\n")).await
+    }
+
+    /// Receiving an upload from the data connection. Failures of the storage are returned
+    /// along with the client, the upload being aborted.
+    async fn receive_data(mut self, mut writer: Box<dyn FileWriter>) -> Result<(Self, io::Result<u64>)> {
+        if let Some(mut reader) = self.data_reader.take() {
             // read the file data in chunks (8KB)
             let mut buffer = [0; 8192];
+            // inflating the data received in `MODE Z`
+            let mut decoder = self.deflate.then(|| ZlibDecoder::new(vec![]));
 
-            // need to check reader
             loop {
-                let bytes_read = reader.read(&mut buffer).await?;
+                let bytes_read = match reader.read(&mut buffer).await {
+                    Ok(bytes_read) => bytes_read,
+                    Err(err) => {
+                        writer.abort().await.ok();
+                        return Ok((self, Err(io::Error::new(err.kind(), DataConnectionLost(err)))));
+                    }
+                };
+                let data = match decoder {
+                    Some(ref mut decoder) if bytes_read == 0 => decoder.try_finish().map(|()| mem::take(decoder.get_mut())),
+                    Some(ref mut decoder) => decoder.write_all(&buffer[..bytes_read]).map(|()| mem::take(decoder.get_mut())),
+                    None => Ok(buffer[..bytes_read].to_vec())
+                }.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, InvalidCompressedData));
+                let result = match data {
+                    Ok(data) if data.is_empty() => Ok(()),
+                    Ok(data) => writer.write(&data).await,
+                    Err(err) => Err(err)
+                };
+                if let Err(err) = result {
+                    writer.abort().await.ok();
+                    return Ok((self, Err(err)));
+                }
                 if bytes_read == 0 {
                     break;
                 }
-                dest_file.write(&buffer[..bytes_read]).await?;
             }
 
-
-            // loop {
-            //     let bytes_read = reader.read(&mut buffer).await?;
-            //     if bytes_read == 0 {
-            //         break;
-            //     }
-            //     // offload the data writing to a separate thread
-            //     // track the time and memory utilized here
-            //     let chunk = buffer[..bytes_read].to_vec();
-            //     file_data = tokio::task::spawn(async move {
-            //         let mut data = file_data;
-            //         data.extend_from_slice(&chunk);
-            //         data
-            //     }).await.map_err(|e| FtpError::Msg(e.to_string()))?;
-            // }
-
-            let file_size = dest_file.metadata().await?.len();
-            Ok((self, file_size))
+            let result = writer.finish().await;
+            Ok((self, result))
         } else {
-            Ok((self, 0))
+            writer.abort().await?;
+            Ok((self, Ok(0)))
+        }
+    }
+
+    /// Applying the filename policy to the virtual path of a new file or directory, returning the
+    /// path to create or the reason of its refusal
+    async fn check_filename(&self, path: &Path) -> result::Result<PathBuf, &'static str> {
+        // the trash and the previous versions are only written by the server
+        if path.iter().any(|component| component == TRASH_DIR || component == VERSIONS_DIR) {
+            return Err("File name reserved by the server");
+        }
+        let (policy, parent, name) = match (&self.ftp_config.filename_policy, path.parent(), path.file_name()) {
+            (Some(policy), Some(parent), Some(name)) => (policy, parent, name.to_string_lossy()),
+            _ => return Ok(path.to_path_buf())
+        };
+
+        let name = policy.check(&name)?;
+        if policy.deny_case_conflicts {
+            let entries = self.storage.list(parent).await.unwrap_or_default();
+            if entries.iter().any(|entry| entry.name != name && entry.name.to_lowercase() == name.to_lowercase()) {
+                return Err("File name differs only in case from an existing one");
+            }
+        }
+        Ok(parent.join(name))
+    }
+
+    /// Getting a path of the directory of the given one which isn't taken, whatever the case
+    async fn unique_path(&self, path: &Path) -> PathBuf {
+        let parent = path.parent().unwrap_or(Path::new("/"));
+        let taken: Vec<String> = self.storage.list(parent).await.unwrap_or_default()
+            .into_iter()
+            .map(|entry| entry.name.to_lowercase())
+            .collect();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        parent.join(unique_name(&name, |name| taken.contains(&name.to_lowercase())))
+    }
+
+    /// Getting the space an upload can take on the disk of a path above `min_free_bytes`
+    async fn free_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        let min_free_bytes = match self.ftp_config.min_free_bytes {
+            Some(min_free_bytes) => min_free_bytes,
+            None => return Ok(None)
+        };
+        match self.storage.available_space(path).await {
+            Ok(Some(available)) if available <= min_free_bytes => Err(io::Error::new(io::ErrorKind::StorageFull, "Not enough free space on the disk")),
+            Ok(available) => Ok(available.map(|available| available - min_free_bytes)),
+            Err(_) => Ok(None)
         }
+    }
 
+    /// Limiting an upload to the free space of the disk
+    fn limit(writer: Box<dyn FileWriter>, free_space: Option<u64>) -> Box<dyn FileWriter> {
+        match free_space {
+            Some(free_space) => Box::new(LimitedFileWriter::new(writer, free_space, io::ErrorKind::StorageFull)),
+            None => writer
+        }
     }
 
+    /// Getting the reply to an upload failing with an error of the storage
+    fn upload_error(err: &io::Error) -> Response {
+        if let Some(violation) = policy_violation(err) {
+            return Response::new(ResponseCode::FileNameNotAllowed, &format!("{}\r\n", violation));
+        }
+        if is_checksum_mismatch(err) {
+            return Response::new(ResponseCode::FileNotFound, "Checksum mismatch, upload discarded\r\n");
+        }
+        if is_invalid_compressed_data(err) {
+            return Response::new(ResponseCode::LocalErrorInProcessing, "Invalid compressed data, upload discarded\r\n");
+        }
+        if is_data_connection_lost(err) {
+            return Response::new(ResponseCode::ConnectionClosed, "Connection closed, transfer aborted\r\n");
+        }
+        match err.kind() {
+            io::ErrorKind::AlreadyExists => Response::new(ResponseCode::FileNotFound, "File already exists, overwriting is not allowed\r\n"),
+            io::ErrorKind::QuotaExceeded => Response::new(ResponseCode::ExceededStorageAllocation, "Storage quota exceeded\r\n"),
+            io::ErrorKind::StorageFull => Response::new(ResponseCode::InsufficientStorageSpace, "Insufficient storage space\r\n"),
+            _ => Response::new(ResponseCode::FileNotFound, "Unable to store the file\r\n")
+        }
+    }
+
+
     fn close_data_connection(&mut self) {
         self.data_reader = None;
         self.data_writer = None;
+        self.encoder = None;
+    }
+
+    /// Sending the last response before the server closes the connection
+    async fn close(mut self, resp: Response) -> Result<Self> {
+        self.close_data_connection();
+        self.closed = true;
+        self.send_response(resp).await
+    }
+
+    /// Resetting the session to the state of a new connection, so another user can log in.
+    /// The failed logins are kept, so the limit of the connection still applies.
+    async fn reinitialize(mut self) -> Result<Self> {
+        if let Some(mut writer) = self.data_writer.take() {
+            let _ = writer.shutdown().await;
+        }
+        self.close_data_connection();
+        self.data_port = None;
+        self.name = None;
+        self.waiting_password = false;
+        self.is_admin = false;
+        self.profile = None;
+        self.pending_profile = None;
+        self.home = None;
+        self.storage = self.storage_root.open(Path::new(""));
+        self.cwd = PathBuf::from("/");
+        self.data_transfer_type = DataTransferType::ASCII;
+        self.hash_algorithm = HashAlgorithm::default();
+        self.range = None;
+        self.deflate = false;
+        self.compression_level = DEFAULT_COMPRESSION_LEVEL;
+        self.send_response(Response::new(ResponseCode::ServiceReadyForNewUser, "Service ready for new user\r\n")).await
     }
 
     async fn quit(mut self) -> Result<Self> {
@@ -594,4 +1335,36 @@ impl Client {
         }
         Ok(self)
     }
-}
\ No newline at end of file
+}
+
+/// Error of an upload in `MODE Z` whose data isn't a valid zlib stream
+#[derive(Debug)]
+struct InvalidCompressedData;
+
+impl std::fmt::Display for InvalidCompressedData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Invalid compressed data")
+    }
+}
+
+impl std::error::Error for InvalidCompressedData {}
+
+fn is_invalid_compressed_data(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<InvalidCompressedData>())
+}
+
+/// Error of an upload whose data connection failed before the end of the file
+#[derive(Debug)]
+struct DataConnectionLost(io::Error);
+
+impl std::fmt::Display for DataConnectionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Data connection lost: {}", self.0)
+    }
+}
+
+impl std::error::Error for DataConnectionLost {}
+
+fn is_data_connection_lost(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<DataConnectionLost>())
+}
@@ -1,7 +1,6 @@
 use std::path::{Path, PathBuf};
 use std::result;
 use std::str::from_utf8;
-use serde::__private::from_utf8_lossy;
 use serde_json::from_str;
 use crate::error::FtpError;
 use crate::utils::{bytes_to_uppercase, get_first_word_and_rest};
@@ -11,25 +10,40 @@ pub type Result<T> = result::Result<T, FtpError>;
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum Command {
+    ACCT(String),
     AUTH,
     CWD(PathBuf),
     CDUP,
+    FEAT,
+    HASH(PathBuf),
     LIST(Option<String>),
+    MFMT(String),
     MKD(PathBuf),
+    MODE(String),
     NOOP,
+    OPTS(String),
     PORT(u16),
     PASS(String),
     PASV,
     PWD,
     QUIT,
+    RANG(String),
+    REIN,
     RETR(PathBuf),
     RMD(PathBuf),
     STOR(PathBuf),
+    STOU(PathBuf),
     SIZE(PathBuf),
+    SITE(String),
     SYST,
     TYPE(DataTransferType),
     UNKNOWN(String),
     USER(String),
+    XCRC(String),
+    XMD5(String),
+    XSHA1(String),
+    XSHA256(String),
+    XSHA512(String),
 }
 
 impl Command {
@@ -54,6 +68,7 @@ impl Command {
 
 
         let command = match command.as_bytes() {
+            b"ACCT" => Command::ACCT(data.to_string()),
             b"AUTH" => Command::AUTH,
             // b"CWD" => Command::CWD(data.and_then(|bytes|  Ok(Path::new(from_utf8(bytes)?).to_path_buf()))?),
             b"CWD" => Command::CWD(Path::new(data).to_path_buf()),
@@ -83,7 +98,19 @@ impl Command {
                 Command::PORT(port)
             },
             b"PWD" => Command::PWD,
+            b"FEAT" => Command::FEAT,
+            b"MODE" => Command::MODE(data.to_string()),
+            b"MFMT" => Command::MFMT(data.to_string()),
+            b"HASH" => Command::HASH(Path::new(data).to_path_buf()),
+            b"OPTS" => Command::OPTS(data.to_string()),
+            b"RANG" => Command::RANG(data.to_string()),
+            b"XCRC" => Command::XCRC(data.to_string()),
+            b"XMD5" => Command::XMD5(data.to_string()),
+            b"XSHA1" => Command::XSHA1(data.to_string()),
+            b"XSHA256" => Command::XSHA256(data.to_string()),
+            b"XSHA512" => Command::XSHA512(data.to_string()),
             b"QUIT" => Command::QUIT,
+            b"REIN" => Command::REIN,
             // b"RETR" => Command::RETR(data.and_then(|bytes| Ok(Path::new(from_utf8(bytes)?).to_path_buf()))?),
             b"RETR" => Command::RETR(Path::new(data).to_path_buf()),
             // b"RMD" => Command::RMD(data.and_then(|bytes| Ok(Path::new(from_utf8(bytes)?).to_path_buf()))?),
@@ -91,8 +118,10 @@ impl Command {
             b"RMD" => Command::RMD(Path::new(data).to_path_buf()),
             b"DELE" => Command::RMD(Path::new(data).to_path_buf()),
             b"STOR" => Command::STOR(Path::new(data).to_path_buf()),
+            b"STOU" => Command::STOU(Path::new(data).to_path_buf()),
             // b"SIZE" => Command::SIZE(data.and_then(|bytes| Ok(PathBuf::from(from_utf8(bytes)?)))?),
             b"SIZE" => Command::SIZE(Path::new(data).to_path_buf()),
+            b"SITE" => Command::SITE(data.to_string()),
             b"SYST" => Command::SYST,
             b"TYPE" => {
                 let err: Result<Command> = Err("Command not implemented".into());
@@ -121,24 +150,39 @@ impl Command {
 impl AsRef<str> for Command {
     fn as_ref(&self) -> &str {
         match *self {
+            Command::ACCT(_) => "ACCT",
             Command::AUTH => "AUTH",
             Command::CWD(_) => "CWD",
             Command::CDUP => "CDUP",
+            Command::FEAT => "FEAT",
+            Command::HASH(_) => "HASH",
             Command::LIST(_) => "LIST",
+            Command::MFMT(_) => "MFMT",
             Command::MKD(_) => "MKD",
+            Command::MODE(_) => "MODE",
             Command::NOOP => "NOOP",
+            Command::OPTS(_) => "OPTS",
             Command::PORT(_) => "PORT",
             Command::PASS(_) => "PASS",
             Command::PASV => "PASV",
             Command::PWD => "PWD",
             Command::QUIT => "QUIT",
+            Command::RANG(_) => "RANG",
+            Command::REIN => "REIN",
             Command::RETR(_) => "RETR",
             Command::STOR(_) => "STOR",
+            Command::STOU(_) => "STOU",
             Command::RMD(_) => "RMD",
             Command::SYST => "SYST",
             Command::SIZE(_) => "SIZE",
+            Command::SITE(_) => "SITE",
             Command::TYPE(_) => "TYPE",
             Command::USER(_) => "USER",
+            Command::XCRC(_) => "XCRC",
+            Command::XMD5(_) => "XMD5",
+            Command::XSHA1(_) => "XSHA1",
+            Command::XSHA256(_) => "XSHA256",
+            Command::XSHA512(_) => "XSHA512",
             Command::UNKNOWN(_) => "UNKN",
         }
     }
@@ -0,0 +1,371 @@
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use crate::storage::{ChunkReader, FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+
+/// First bytes of the encrypted files, authenticated along with their wrapped key
+const MAGIC: &[u8; 8] = b"FTPENC01";
+/// Nonce and AES-GCM encryption of the 32-byte key of the file with the master key
+const WRAPPED_KEY_SIZE: usize = 12 + 32 + TAG_SIZE;
+/// Random part of the nonces of the chunks, followed by their number and a last chunk flag
+const PREFIX_SIZE: usize = 7;
+const HEADER_SIZE: usize = MAGIC.len() + WRAPPED_KEY_SIZE + PREFIX_SIZE;
+/// Size of the plaintext of the chunks the files are encrypted in, the last one being shorter
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Encryption of the files of a storage, with a master key read from a file holding 32 bytes,
+/// raw or as 64 hexadecimal digits, e.g. made with `openssl rand -hex 32`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    pub key_file: PathBuf,
+}
+
+impl EncryptionConfig {
+    async fn master_key(&self) -> io::Result<Aes256Gcm> {
+        let content = tokio::fs::read(&self.key_file).await?;
+        let text = String::from_utf8_lossy(&content);
+        let key = match text.trim() {
+            hex if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+                (0..32).map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap_or_default()).collect()
+            },
+            _ if content.len() == 32 => content,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The key file must hold 32 bytes"))
+        };
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+/// Storage encrypting the content of the files of another storage with AES-256-GCM. Every file
+/// has its own key, stored in its header wrapped by the master key, and is encrypted in chunks
+/// which can be decrypted from any of them. The sizes are the sizes of the plaintexts. The files
+/// without the header, stored before the encryption was enabled, are passed through as they are.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    master: Aes256Gcm,
+}
+
+impl EncryptedStorage {
+    pub async fn open(inner: Arc<dyn StorageBackend>, config: &EncryptionConfig) -> io::Result<Self> {
+        Ok(EncryptedStorage { inner, master: config.master_key().await? })
+    }
+
+    /// Checking if a file starts with the header of the encrypted files
+    async fn is_encrypted(&self, path: &Path) -> bool {
+        let mut magic = [0; MAGIC.len()];
+        match self.inner.open_read(path, 0).await {
+            Ok(mut reader) => matches!(read_full(&mut reader, &mut magic).await, Ok(size) if size == MAGIC.len()) && &magic == MAGIC,
+            Err(_) => false
+        }
+    }
+
+    async fn plaintext_metadata(&self, path: &Path, mut metadata: FileMetadata) -> FileMetadata {
+        if !metadata.is_dir && self.is_encrypted(path).await {
+            metadata.size = plaintext_size(metadata.size);
+        }
+        metadata
+    }
+
+    /// Getting the cipher of a file and the prefix of its nonces from its header
+    fn file_cipher(&self, header: &[u8; HEADER_SIZE]) -> io::Result<(Aes256Gcm, [u8; PREFIX_SIZE])> {
+        let (magic, rest) = header.split_at(MAGIC.len());
+        let (wrapped, prefix) = rest.split_at(WRAPPED_KEY_SIZE);
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an encrypted file"));
+        }
+        let (nonce, wrapped) = wrapped.split_at(12);
+        let key = self.master.decrypt(Nonce::from_slice(nonce), Payload { msg: wrapped, aad: MAGIC })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unable to decrypt the key of the file"))?;
+        Ok((Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)), prefix.try_into().unwrap_or_default()))
+    }
+}
+
+/// Function to get the size of the plaintext of an encrypted file from its own size
+pub fn plaintext_size(size: u64) -> u64 {
+    let body = size.saturating_sub(HEADER_SIZE as u64);
+    let chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+    body / chunk * CHUNK_SIZE as u64 + (body % chunk).saturating_sub(TAG_SIZE as u64)
+}
+
+fn chunk_nonce(prefix: &[u8; PREFIX_SIZE], index: u32, last: bool) -> Nonce<U12> {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Encrypted file corrupted or truncated")
+}
+
+/// Function to read as many bytes as the buffer holds, less only at the end of the stream
+async fn read_full(reader: &mut FileReader, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() {
+        match reader.read(&mut buffer[size..]).await? {
+            0 => break,
+            read => size += read
+        }
+    }
+    Ok(size)
+}
+
+/// Function to decrypt the chunks of a file from the one at `index`, sending their plaintext
+/// without its first `skip` bytes. The last chunk is the only one decrypting with the last flag,
+/// so that truncated files are detected.
+async fn decrypt_chunks(mut reader: FileReader, cipher: Aes256Gcm, prefix: [u8; PREFIX_SIZE], mut index: u32, mut skip: usize, sender: &mpsc::Sender<io::Result<Vec<u8>>>) -> io::Result<()> {
+    let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+    loop {
+        let size = read_full(&mut reader, &mut chunk).await?;
+        let data = &chunk[..size];
+        let (plaintext, last) = match cipher.decrypt(&chunk_nonce(&prefix, index, false), data) {
+            Ok(plaintext) if size == chunk.len() => (plaintext, false),
+            _ => (cipher.decrypt(&chunk_nonce(&prefix, index, true), data).map_err(|_| corrupted())?, true)
+        };
+
+        if plaintext.len() > skip && sender.send(Ok(plaintext[skip..].to_vec())).await.is_err() {
+            // the reader was dropped
+            return Ok(());
+        }
+        skip = skip.saturating_sub(plaintext.len());
+        if last {
+            return match reader.read(&mut chunk[..1]).await? {
+                0 => Ok(()),
+                _ => Err(corrupted())
+            };
+        }
+        index += 1;
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let metadata = self.inner.metadata(path).await?;
+        Ok(self.plaintext_metadata(path, metadata).await)
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        let mut entries = vec![];
+        for entry in self.inner.list(path).await? {
+            let metadata = self.plaintext_metadata(&path.join(&entry.name), entry.metadata).await;
+            entries.push(FileEntry { name: entry.name, metadata });
+        }
+        Ok(entries)
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let mut reader = self.inner.open_read(path, 0).await?;
+        let mut header = [0; HEADER_SIZE];
+        let size = read_full(&mut reader, &mut header).await?;
+        if size < MAGIC.len() || &header[..MAGIC.len()] != MAGIC {
+            return self.inner.open_read(path, offset).await;
+        }
+        if size < HEADER_SIZE {
+            return Err(corrupted());
+        }
+        let (cipher, prefix) = self.file_cipher(&header)?;
+
+        // starting from the chunk holding the offset, none past the end
+        if offset > 0 && offset >= self.metadata(path).await?.size {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let index = offset / CHUNK_SIZE as u64;
+        if index > 0 {
+            reader = self.inner.open_read(path, HEADER_SIZE as u64 + index * (CHUNK_SIZE + TAG_SIZE) as u64).await?;
+        }
+        let index = u32::try_from(index).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let skip = (offset % CHUNK_SIZE as u64) as usize;
+
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(err) = decrypt_chunks(reader, cipher, prefix, index, skip, &sender).await {
+                sender.send(Err(err)).await.ok();
+            }
+        });
+        Ok(Box::new(ChunkReader::new(receiver)))
+    }
+
+    /// Encrypted files are written whole, they can't be appended to
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        if append {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Appending to encrypted files isn't supported"));
+        }
+
+        let key = Aes256Gcm::generate_key(OsRng);
+        let mut nonce = [0; 12];
+        let mut prefix = [0; PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        OsRng.fill_bytes(&mut prefix);
+        let wrapped = self.master.encrypt(Nonce::from_slice(&nonce), Payload { msg: &key, aad: MAGIC })
+            .map_err(|_| io::Error::other("Unable to encrypt the key of the file"))?;
+
+        let mut inner = self.inner.open_write(path, false, create_new).await?;
+        inner.write(&[&MAGIC[..], &nonce, &wrapped, &prefix].concat()).await?;
+        Ok(Box::new(EncryptedFileWriter { inner, cipher: Aes256Gcm::new(&key), prefix, index: 0, buffer: vec![], synced: false }))
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(path, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(path, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(path).await
+    }
+}
+
+/// Upload encrypted chunk by chunk, the last chunk being written when it is finished
+struct EncryptedFileWriter {
+    inner: Box<dyn FileWriter>,
+    cipher: Aes256Gcm,
+    prefix: [u8; PREFIX_SIZE],
+    index: u32,
+    /// Plaintext of the chunk being filled
+    buffer: Vec<u8>,
+    /// Whether the upload has to be made durable once its last chunk is written
+    synced: bool,
+}
+
+impl EncryptedFileWriter {
+    async fn write_chunk(&mut self, chunk: &[u8], last: bool) -> io::Result<()> {
+        let ciphertext = self.cipher.encrypt(&chunk_nonce(&self.prefix, self.index, last), chunk)
+            .map_err(|_| io::Error::other("Unable to encrypt the file"))?;
+        self.index = self.index.checked_add(1).ok_or_else(|| io::Error::new(io::ErrorKind::FileTooLarge, "File too large to be encrypted"))?;
+        self.inner.write(&ciphertext).await
+    }
+}
+
+#[async_trait]
+impl FileWriter for EncryptedFileWriter {
+    /// Keeping at least a byte in the buffer, so that the last chunk is never empty unless the file is
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() > CHUNK_SIZE {
+            let rest = self.buffer.split_off(CHUNK_SIZE);
+            let chunk = mem::replace(&mut self.buffer, rest);
+            self.write_chunk(&chunk, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.synced = true;
+        self.inner.sync().await
+    }
+
+    async fn finish(mut self: Box<Self>) -> io::Result<u64> {
+        let chunk = mem::take(&mut self.buffer);
+        self.write_chunk(&chunk, true).await?;
+        if self.synced {
+            self.inner.sync().await?;
+        }
+        self.inner.finish().await.map(plaintext_size)
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+#[test]
+fn test_plaintext_size() {
+    let chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+    let header = HEADER_SIZE as u64;
+    assert_eq!(plaintext_size(header + TAG_SIZE as u64), 0);
+    assert_eq!(plaintext_size(header + 5 + TAG_SIZE as u64), 5);
+    assert_eq!(plaintext_size(header + chunk), CHUNK_SIZE as u64);
+    assert_eq!(plaintext_size(header + 2 * chunk + 5 + TAG_SIZE as u64), 2 * CHUNK_SIZE as u64 + 5);
+}
+
+#[tokio::test]
+async fn test_encrypted_storage() {
+    use crate::memory_storage::MemoryStorage;
+
+    let key_file = std::env::temp_dir().join(format!("ftp-rustified-test-{}.key", std::process::id()));
+    std::fs::write(&key_file, "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n").unwrap();
+    let config = EncryptionConfig { key_file: key_file.clone() };
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let storage = EncryptedStorage::open(inner.clone(), &config).await.unwrap();
+
+    async fn read(storage: &dyn StorageBackend, path: &str, offset: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        storage.open_read(Path::new(path), offset).await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    let content: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+    for (path, size) in [("/empty", 0), ("/chunk", CHUNK_SIZE), ("/big", content.len())] {
+        let mut writer = storage.open_write(Path::new(path), false, false).await.unwrap();
+        for part in content[..size].chunks(10000) {
+            writer.write(part).await.unwrap();
+        }
+        assert_eq!(writer.finish().await.unwrap(), size as u64);
+        assert_eq!(storage.metadata(Path::new(path)).await.unwrap().size, size as u64);
+        assert_eq!(read(&storage, path, 0).await.unwrap(), content[..size]);
+    }
+    assert_eq!(read(&storage, "/big", CHUNK_SIZE as u64 + 7).await.unwrap(), content[CHUNK_SIZE + 7..]);
+    assert_eq!(read(&storage, "/big", content.len() as u64).await.unwrap(), b"");
+    assert_eq!(read(&storage, "/chunk", CHUNK_SIZE as u64).await.unwrap(), b"");
+
+    // the stored content is encrypted, and the changes are detected
+    let mut stored = vec![];
+    inner.open_read(Path::new("/big"), 0).await.unwrap().read_to_end(&mut stored).await.unwrap();
+    assert!(!stored.windows(64).any(|window| window == &content[1000..1064]));
+    for tampered in [{ let mut data = stored.clone(); data[HEADER_SIZE + 10] ^= 1; data }, stored[..stored.len() - (5 + TAG_SIZE)].to_vec()] {
+        let mut writer = inner.open_write(Path::new("/tampered"), false, false).await.unwrap();
+        writer.write(&tampered).await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!(read(&storage, "/tampered", 0).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // the files stored before the encryption was enabled are read as they are
+    let mut writer = inner.open_write(Path::new("/plain.txt"), false, false).await.unwrap();
+    writer.write(b"Hello, World!").await.unwrap();
+    writer.finish().await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/plain.txt")).await.unwrap().size, 13);
+    assert_eq!(read(&storage, "/plain.txt", 7).await.unwrap(), b"World!");
+    let sizes: Vec<u64> = storage.list(Path::new("/")).await.unwrap().into_iter().filter(|entry| entry.name == "plain.txt" || entry.name == "big").map(|entry| entry.metadata.size).collect();
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes.contains(&13) && sizes.contains(&(content.len() as u64)));
+
+    // another master key can't read the files
+    std::fs::write(&key_file, [7; 32]).unwrap();
+    let other = EncryptedStorage::open(inner.clone(), &config).await.unwrap();
+    assert_eq!(read(&other, "/big", 0).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    std::fs::remove_file(&key_file).ok();
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+/// Rules of the names of the uploaded files and created directories. Names breaking them are
+/// refused, or rewritten with `_` in place of the offending characters when `sanitize` is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilenamePolicy {
+    pub sanitize: bool,
+    pub deny_control_chars: bool,
+    pub deny_leading_dash: bool,
+    /// Refusing names differing only in case from another entry of their directory
+    pub deny_case_conflicts: bool,
+    /// Only allowing letters, digits, `.`, `_` and `-`, the portable filename character set
+    pub portable_only: bool,
+    /// Maximum length of the names, in characters
+    pub max_length: Option<usize>,
+}
+
+impl Default for FilenamePolicy {
+    fn default() -> Self {
+        FilenamePolicy {
+            sanitize: false,
+            deny_control_chars: true,
+            deny_leading_dash: true,
+            deny_case_conflicts: true,
+            portable_only: false,
+            max_length: Some(255),
+        }
+    }
+}
+
+impl FilenamePolicy {
+    fn allowed_char(&self, c: char) -> bool {
+        !(self.deny_control_chars && c.is_control())
+            && !(self.portable_only && !(c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'))
+    }
+
+    /// Checking a name, returning the one to use or the reason of its refusal
+    pub fn check(&self, name: &str) -> Result<String, &'static str> {
+        let mut name = name.to_string();
+
+        if !name.chars().all(|c| self.allowed_char(c)) {
+            if !self.sanitize {
+                return Err("File name contains forbidden characters");
+            }
+            name = name.chars().map(|c| if self.allowed_char(c) { c } else { '_' }).collect();
+        }
+        if self.deny_leading_dash && name.starts_with('-') {
+            if !self.sanitize {
+                return Err("File name can't start with a dash");
+            }
+            name = format!("_{}", name.trim_start_matches('-'));
+        }
+        if let Some(max_length) = self.max_length {
+            if name.chars().count() > max_length {
+                if !self.sanitize {
+                    return Err("File name too long");
+                }
+                name = name.chars().take(max_length).collect();
+            }
+        }
+        Ok(name)
+    }
+}
+
+/// Function to get a name which isn't taken, numbering the name before its extension,
+/// e.g. `data.1.csv` for `data.csv`
+pub fn unique_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(name) {
+        return name.to_string();
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 => (&name[..index], &name[index..]),
+        _ => (name, "")
+    };
+    (1..).map(|number| format!("{}.{}{}", stem, number, extension)).find(|name| !taken(name)).unwrap_or_default()
+}
+
+#[test]
+fn test_filename_policy() {
+    let policy = FilenamePolicy::default();
+    assert_eq!(policy.check("report 2024.csv"), Ok("report 2024.csv".to_string()));
+    assert!(policy.check("bad\nname.csv").is_err());
+    assert!(policy.check("-rf").is_err());
+    assert!(policy.check(&"a".repeat(256)).is_err());
+
+    let policy = FilenamePolicy { sanitize: true, portable_only: true, max_length: Some(12), ..FilenamePolicy::default() };
+    assert_eq!(policy.check("--rapport été.csv"), Ok("_rapport__t_".to_string()));
+    assert_eq!(policy.check("a\tb.txt"), Ok("a_b.txt".to_string()));
+}
+
+#[test]
+fn test_unique_name() {
+    let taken = ["data.csv", "data.1.csv", "README"];
+    assert_eq!(unique_name("data.csv", |name| taken.contains(&name)), "data.2.csv");
+    assert_eq!(unique_name("README", |name| taken.contains(&name)), "README.1");
+    assert_eq!(unique_name("notes.txt", |name| taken.contains(&name)), "notes.txt");
+    assert_eq!(unique_name(".profile", |name| name == ".profile"), ".profile.1");
+}
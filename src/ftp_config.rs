@@ -1,16 +1,171 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use dotenv::dotenv;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
+use crate::checksum::UploadChecksums;
+use crate::encryption::EncryptionConfig;
+use crate::filename_policy::FilenamePolicy;
+use crate::ftp_group::FtpGroup;
 use crate::ftp_user::FtpUser;
+use crate::ip_filter::IpFilter;
+use crate::mount::Mount;
+use crate::trash::TrashConfig;
+use crate::upload_policy::UploadPolicy;
 use crate::utils::get_content;
+use crate::versioning::OverwritePolicy;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FtpConfig {
     pub port: u16,
     pub addr: String,
     pub admin: Option<FtpUser>,
-    pub users: Vec<FtpUser>
+    pub users: Vec<FtpUser>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anonymous: Option<AnonymousConfig>,
+    /// Base directory of the relative home directories, the server's root directory by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home_base: Option<PathBuf>,
+    /// Home directory of the users without one, e.g. `%u`. Without it they share the server's root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_home: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<FtpGroup>,
+    #[serde(default)]
+    pub login_limits: LoginLimits,
+    /// Networks allowed to connect to the server, checked before the welcome message
+    #[serde(default, skip_serializing_if = "IpFilter::is_empty")]
+    pub ip_filter: IpFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    /// Folders mounted in the view of every user, in addition to the mounts of their groups
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<Mount>,
+    /// Uploading to a hidden temporary file renamed into place once complete, so that no
+    /// partial file ever shows up under the destination name
+    #[serde(default)]
+    pub atomic_uploads: bool,
+    /// Free space kept on the disks of the local storage, uploads failing when they would go below it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_bytes: Option<u64>,
+    /// Rules of the files uploaded with `STOR`, every policy of a folder applying to its uploads
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upload_policies: Vec<UploadPolicy>,
+    /// Rules of the names of the uploaded files and created directories, any name being accepted without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename_policy: Option<FilenamePolicy>,
+    /// What happens to the files replaced by uploads, outside of the mounts with their own policy
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+    /// Moving the deleted files and directories to a `.trash` directory, at the root of the home
+    /// of the user or of the mount they were deleted from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash: Option<TrashConfig>,
+    /// Computing the SHA-256 of the uploads as they are received, to record it or check it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_checksums: Option<UploadChecksums>,
+    /// Encrypting the content of the files of the users' home directories, the mounts having their own `encryption`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>
+}
+
+/// Limits of the failed logins, a limit of `0` disabling it.
+/// Every failed login is answered after a delay doubling with each failure up to `max_delay_ms`,
+/// connections are closed after `max_failures_per_connection` failures and usernames and source
+/// IPs are banned for `ban_secs` after too many failures within `failure_window_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginLimits {
+    pub max_failures_per_connection: u32,
+    pub max_failures_per_user: u32,
+    pub max_failures_per_ip: u32,
+    pub failure_window_secs: u64,
+    pub ban_secs: u64,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        LoginLimits {
+            max_failures_per_connection: 3,
+            max_failures_per_user: 10,
+            max_failures_per_ip: 20,
+            failure_window_secs: 15 * 60,
+            ban_secs: 15 * 60,
+            base_delay_ms: 500,
+            max_delay_ms: 8000
+        }
+    }
+}
+
+/// Anonymous FTP, accepting the `anonymous` and `ftp` users with any e-mail address as password.
+/// Anonymous users get read-only access to `root`, and can only upload into the `incoming`
+/// folder (relative to `root`) which they can't list or download from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymousConfig {
+    pub root: PathBuf,
+    #[serde(default)]
+    pub incoming: Option<PathBuf>
+}
+
+/// Authentication backend used to verify the users, the JSON `admin` and `users` by default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuthConfig {
+    Json,
+    Htpasswd {
+        path: PathBuf,
+        #[serde(default)]
+        admins: Vec<String>
+    },
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default = "default_auth_timeout")]
+        timeout_secs: u64
+    }
+}
+
+/// Storage backend the sessions are served from, the server's root directory by default
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local,
+    /// Tree kept in memory, empty at startup and lost when the server stops
+    Memory,
+    S3(S3Config)
+}
+
+/// Bucket of an S3 compatible object storage, or a prefix of it, served as a directory tree.
+/// The credentials default to the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` variables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Endpoint of the service, e.g. `http://127.0.0.1:9000`, AWS by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Addressing the bucket in the path of the URLs instead of the host name, as MinIO expects
+    #[serde(default)]
+    pub path_style: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_auth_timeout() -> u64 {
+    10
 }
 
 impl FtpConfig {
@@ -37,9 +192,11 @@ impl FtpConfig {
                 users: vec![
                     FtpUser {
                         username: "admin".to_string(),
-                        password: "admin".to_string()
+                        password: "admin".to_string(),
+                        ..FtpUser::default()
                     }
-                ]
+                ],
+                ..FtpConfig::default()
             };
 
             let content = serde_json::to_string(&default_server_config).unwrap();
@@ -59,18 +216,23 @@ fn test_ftp_config() {
         addr: "0.0.0.0".to_string(),
         admin: Some(FtpUser {
             username: "admin".to_string(),
-            password: "admin".to_string()
+            password: "admin".to_string(),
+            ..FtpUser::default()
         }),
         users: vec![
             FtpUser {
                 username: "user1".to_string(),
-                password: "user1".to_string()
+                password: "user1".to_string(),
+                home: Some("%u".to_string()),
+                ..FtpUser::default()
             },
             FtpUser {
                 username: "user2".to_string(),
-                password: "user2".to_string()
+                password: "user2".to_string(),
+                ..FtpUser::default()
             }
-        ]
+        ],
+        ..FtpConfig::default()
     };
     assert_eq!(config.port, 2001);
     assert_eq!(config.addr, "0.0.0.0".to_string());
@@ -81,6 +243,22 @@ fn test_ftp_config() {
         panic!("Admin user not found");
     }
     assert_eq!(config.users[0].username, "user1".to_string());
+    assert_eq!(config.users[0].home, Some("%u".to_string()));
+    assert_eq!(config.users[1].home, None);
+    assert!(config.users[1].enabled);
+}
+
+#[test]
+fn test_user_validity_config() {
+    let user: FtpUser = serde_json::from_str(
+        r#"{"username":"vendor","password":"vendor","valid_from":"2024-01-01T00:00:00Z","expires_at":"2024-02-01T12:00:00+02:00"}"#
+    ).unwrap();
+    assert!(user.enabled);
+    assert_eq!(user.valid_from.unwrap().to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    assert_eq!(user.expires_at.unwrap().to_rfc3339(), "2024-02-01T10:00:00+00:00");
+
+    let user: FtpUser = serde_json::from_str(r#"{"username":"old","password":"old","enabled":false}"#).unwrap();
+    assert!(!user.enabled);
 }
 
 #[tokio::test]
@@ -98,4 +276,39 @@ async fn test_read_file() {
     assert_eq!(config.users[0].username, "user".to_string());
     assert_eq!(config.users[0].password, "user".to_string());
 
+}
+
+#[test]
+fn test_auth_config() {
+    let config: FtpConfig = serde_json::from_str(
+        r#"{"port":2001,"addr":"0.0.0.0","admin":null,"users":[],"auth":{"type":"htpasswd","path":"users.htpasswd","admins":["root"]}}"#
+    ).unwrap();
+    match config.auth {
+        Some(AuthConfig::Htpasswd { path, admins }) => {
+            assert_eq!(path, PathBuf::from("users.htpasswd"));
+            assert_eq!(admins, vec!["root".to_string()]);
+        },
+        _ => panic!("Htpasswd auth not parsed")
+    }
+}
+
+#[test]
+fn test_anonymous_config() {
+    let config: FtpConfig = serde_json::from_str(
+        r#"{"port":2001,"addr":"0.0.0.0","admin":null,"users":[],"anonymous":{"root":"PUBLIC","incoming":"incoming"}}"#
+    ).unwrap();
+    let anonymous = config.anonymous.expect("Anonymous config not parsed");
+    assert_eq!(anonymous.root, PathBuf::from("PUBLIC"));
+    assert_eq!(anonymous.incoming, Some(PathBuf::from("incoming")));
+    assert_eq!(config.login_limits, LoginLimits::default());
+}
+
+#[test]
+fn test_login_limits_config() {
+    let config: FtpConfig = serde_json::from_str(
+        r#"{"port":2001,"addr":"0.0.0.0","admin":null,"users":[],"login_limits":{"max_failures_per_ip":0,"ban_secs":60}}"#
+    ).unwrap();
+    assert_eq!(config.login_limits.max_failures_per_ip, 0);
+    assert_eq!(config.login_limits.ban_secs, 60);
+    assert_eq!(config.login_limits.max_failures_per_connection, 3);
 }
\ No newline at end of file
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use crate::auth::UserProfile;
+use crate::mount::Mount;
+use crate::permissions::{PathPermissions, Permissions};
+
+/// Group of users sharing permissions and mounted folders.
+/// Users are members when they are listed in `members` or list the group in their own `groups`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FtpGroup {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_permissions: Vec<PathPermissions>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<Mount>
+}
+
+impl FtpGroup {
+    pub fn has_member(&self, profile: &UserProfile) -> bool {
+        self.members.contains(&profile.username) || profile.groups.contains(&self.name)
+    }
+}
+
+/// Function to merge the groups of the user into its profile.
+/// The permissions of the groups are added to the user's own ones, users without permissions of
+/// their own only getting the ones of their groups. The path permissions of the user take
+/// precedence over the ones of its groups.
+pub fn apply_groups(profile: &mut UserProfile, groups: &[FtpGroup]) {
+    let groups: Vec<&FtpGroup> = groups.iter().filter(|group| group.has_member(profile)).collect();
+    let mut path_permissions = vec![];
+
+    for group in groups {
+        if let Some(ref permissions) = group.permissions {
+            profile.permissions = Some(match profile.permissions.take() {
+                Some(own) => own.union(permissions),
+                None => permissions.clone()
+            });
+        }
+        path_permissions.extend(group.path_permissions.iter().cloned());
+        profile.mounts.extend(group.mounts.iter().cloned());
+    }
+
+    path_permissions.append(&mut profile.path_permissions);
+    profile.path_permissions = path_permissions;
+}
+
+#[test]
+fn test_apply_groups() {
+    use std::path::PathBuf;
+    use crate::permissions::Permission;
+
+    let groups: Vec<FtpGroup> = serde_json::from_str(r#"[
+        {"name":"finance","permissions":["list","read"],"mounts":[{"path":"/shared/finance","source":"finance"}]},
+        {"name":"auditors","members":["bob"],"permissions":["list"],"path_permissions":[{"path":"/shared","permissions":[]}]},
+        {"name":"hr","mounts":[{"path":"/shared/hr","source":"hr"}]}
+    ]"#).unwrap();
+
+    let mut alice = UserProfile {
+        username: "alice".to_string(),
+        groups: vec!["finance".to_string()],
+        ..UserProfile::default()
+    };
+    apply_groups(&mut alice, &groups);
+    assert_eq!(alice.permissions, Some(Permissions::read_only()));
+    assert_eq!(alice.mounts.len(), 1);
+    assert_eq!(alice.mounts[0].source, PathBuf::from("finance"));
+
+    let mut bob = UserProfile {
+        username: "bob".to_string(),
+        groups: vec!["finance".to_string()],
+        permissions: Some(Permissions::new(vec![Permission::Write])),
+        path_permissions: vec![PathPermissions { path: PathBuf::from("/shared"), permissions: Permissions::all() }],
+        ..UserProfile::default()
+    };
+    apply_groups(&mut bob, &groups);
+    let permissions = bob.permissions.unwrap();
+    assert!(permissions.allows(Permission::Write) && permissions.allows(Permission::Read));
+    assert_eq!(bob.path_permissions.last().unwrap().permissions, Permissions::all());
+
+    let mut carol = UserProfile { username: "carol".to_string(), ..UserProfile::default() };
+    apply_groups(&mut carol, &groups);
+    assert_eq!(carol.permissions, None);
+    assert!(carol.mounts.is_empty());
+}
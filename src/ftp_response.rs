@@ -4,18 +4,31 @@ use crate::ftp_response_code::ResponseCode;
 #[derive(Debug)]
 pub struct Response {
     pub code: ResponseCode,
-    pub message: String
+    pub message: String,
+    /// Lines of a multi-line reply between its first line, the message, and its `End` line
+    pub lines: Vec<String>
 }
 
 impl Response {
     pub fn new(code: ResponseCode, message: &str) -> Self {
         println!("\t\tCreating new response: \"{:?}\"",Response {
             code: code.clone(), // code not getting converted to number
-            message: message.to_string()
+            message: message.to_string(),
+            lines: vec![]
         });
         Response {
             code,
-            message: message.to_string()
+            message: message.to_string(),
+            lines: vec![]
+        }
+    }
+
+    /// Creating a multi-line reply, e.g. the list of features of `FEAT`
+    pub fn multiline(code: ResponseCode, message: &str, lines: Vec<String>) -> Self {
+        Response {
+            code,
+            message: message.to_string(),
+            lines
         }
     }
 
@@ -30,7 +43,12 @@ impl Response {
     }
 
     pub fn to_string(self) -> String {
-        format!("{:?} {}",self.code as u32,self.message)
+        if self.lines.is_empty() {
+            return format!("{:?} {}",self.code as u32,self.message);
+        }
+        let code = self.code as u32;
+        let lines: String = self.lines.iter().map(|line| format!(" {}\r\n", line)).collect();
+        format!("{}-{}{}{} End\r\n", code, self.message, lines, code)
     }
 }
 
@@ -38,4 +56,7 @@ impl Response {
 fn test_response() {
     let response = Response::new(ResponseCode::Ok, "Hello, World!");
     assert_eq!(response.to_string(), "200 Hello, World!");
+
+    let response = Response::multiline(ResponseCode::SystemStatus, "Features:\r\n", vec!["SIZE".to_string(), "MDTM".to_string()]);
+    assert_eq!(response.to_string(), "211-Features:\r\n SIZE\r\n MDTM\r\n211 End\r\n");
 }
\ No newline at end of file
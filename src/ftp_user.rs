@@ -1,7 +1,70 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use crate::ftp_config::StorageConfig;
+use crate::ip_filter::IpFilter;
+use crate::quota::Quota;
+use crate::permissions::{PathPermissions, Permissions};
 
 #[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct FtpUser {
     pub username: String,
-    pub password: String
-}
\ No newline at end of file
+    pub password: String,
+    /// Home directory the user is confined to, absolute or relative to `home_base`.
+    /// `%u` is replaced by the username.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    /// Operations allowed to the user, all of them by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+    /// Permissions overriding `permissions` for specific paths of the home directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_permissions: Vec<PathPermissions>,
+    /// Names of the groups the user is a member of
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Networks the user is allowed to log in from
+    #[serde(default, skip_serializing_if = "IpFilter::is_empty")]
+    pub ip_filter: IpFilter,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Start of the validity of the account, as an RFC 3339 date e.g. `2024-01-31T00:00:00Z`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<DateTime<Utc>>,
+    /// End of the validity of the account, the sessions still opened being closed at that time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Base32 secret of the time-based one-time codes required in addition to the password,
+    /// either appended to it or sent with `ACCT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    /// Storage the user is confined to instead of a home directory, e.g. an S3 bucket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    /// Limits of the files of the user's home directory or storage, mounts excluded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for FtpUser {
+    fn default() -> Self {
+        FtpUser {
+            username: String::new(),
+            password: String::new(),
+            home: None,
+            permissions: None,
+            path_permissions: vec![],
+            groups: vec![],
+            ip_filter: IpFilter::default(),
+            enabled: true,
+            valid_from: None,
+            expires_at: None,
+            totp_secret: None,
+            storage: None,
+            quota: None
+        }
+    }
+}
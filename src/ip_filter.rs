@@ -0,0 +1,88 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// Network in CIDR notation, e.g. `10.0.0.0/8`, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Network(IpNet);
+
+impl Network {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Network(net.trunc()));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| Network(IpNet::from(ip)))
+            .map_err(|_| format!("Invalid network \"{}\"", s))
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Network> for String {
+    fn from(value: Network) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Allow and deny lists of networks. Denied networks take precedence, and an empty allow list
+/// allows every address which isn't denied.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IpFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Network>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Network>,
+}
+
+impl IpFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+#[test]
+fn test_ip_filter() {
+    let filter: IpFilter = serde_json::from_str(
+        r#"{"allow":["10.0.0.0/8","192.168.1.10","2001:db8::/32"],"deny":["10.1.0.0/16"]}"#
+    ).unwrap();
+
+    assert!(filter.allows("10.2.3.4".parse().unwrap()));
+    assert!(!filter.allows("10.1.3.4".parse().unwrap()));
+    assert!(filter.allows("192.168.1.10".parse().unwrap()));
+    assert!(!filter.allows("192.168.1.11".parse().unwrap()));
+    assert!(filter.allows("::ffff:10.2.3.4".parse().unwrap()));
+    assert!(filter.allows("2001:db8::1".parse().unwrap()));
+    assert!(IpFilter::default().allows("8.8.8.8".parse().unwrap()));
+    assert!(serde_json::from_str::<IpFilter>(r#"{"deny":["10.0.0.0/33"]}"#).is_err());
+}
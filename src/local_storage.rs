@@ -0,0 +1,208 @@
+use std::io;
+use std::io::SeekFrom;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use tokio::fs::{create_dir_all, read_dir, remove_dir_all, remove_file, File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+use crate::utils::normalize_path;
+
+/// Storage backend serving a directory of the local filesystem.
+/// Symbolic links can't be followed outside of the directory.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    /// Getting the host path of a virtual path, without checking where it leads
+    fn host_path(&self, path: &Path) -> PathBuf {
+        let path = normalize_path(path);
+        self.root.join(path.strip_prefix("/").unwrap_or(&path))
+    }
+
+    /// Getting the host path of a virtual path, refusing the ones leading outside of the root.
+    /// The deepest existing ancestor is checked for the paths which don't exist yet.
+    fn checked_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let host_path = self.host_path(path);
+        let root = self.root.canonicalize()?;
+
+        let existing = host_path.ancestors()
+            .find_map(|ancestor| ancestor.canonicalize().ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        if !existing.starts_with(&root) {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok(host_path)
+    }
+}
+
+pub fn file_metadata(metadata: &std::fs::Metadata) -> FileMetadata {
+    FileMetadata {
+        is_dir: metadata.is_dir(),
+        size: metadata.len(),
+        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        mode: metadata.permissions().mode() & 0o777,
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let path = self.checked_path(path)?;
+        Ok(file_metadata(&tokio::fs::metadata(path).await?))
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        let path = self.checked_path(path)?;
+        let mut entries = vec![];
+        let mut dir_reader = read_dir(path).await?;
+
+        while let Some(entry) = dir_reader.next_entry().await? {
+            // skipping the entries we can't get the metadata of, e.g. broken links
+            if let Ok(metadata) = tokio::fs::metadata(entry.path()).await {
+                entries.push(FileEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    metadata: file_metadata(&metadata),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let path = self.checked_path(path)?;
+        let mut file = File::open(path).await?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Box::new(file))
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        let path = self.checked_path(path)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(create_new)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .await?;
+        Ok(Box::new(LocalFileWriter { file }))
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        create_dir_all(self.checked_path(path)?).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let host_path = self.checked_path(path)?;
+        if host_path == self.root {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+
+        if tokio::fs::symlink_metadata(&host_path).await?.is_dir() {
+            remove_dir_all(host_path).await
+        } else {
+            remove_file(host_path).await
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        tokio::fs::rename(self.checked_path(from)?, self.checked_path(to)?).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let file = File::open(self.checked_path(path)?).await?.into_std().await;
+        tokio::task::spawn_blocking(move || file.set_modified(modified)).await?
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let (path, name, value) = (self.checked_path(path)?, name.to_string(), value.to_vec());
+        tokio::task::spawn_blocking(move || xattr::set(path, name, &value)).await?
+    }
+
+    /// Free space of the disk of the deepest existing directory of the path
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        let path = self.checked_path(path)?;
+        tokio::task::spawn_blocking(move || {
+            let dir = path.ancestors().find(|dir| dir.is_dir()).unwrap_or(&path);
+            fs4::available_space(dir).map(Some)
+        }).await?
+    }
+}
+
+/// Upload written straight into its destination file
+struct LocalFileWriter {
+    file: File,
+}
+
+#[async_trait]
+impl FileWriter for LocalFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await
+    }
+
+    async fn finish(mut self: Box<Self>) -> io::Result<u64> {
+        self.file.flush().await?;
+        Ok(self.file.metadata().await?.len())
+    }
+
+    /// Interrupted uploads are kept as partial files
+    async fn abort(mut self: Box<Self>) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+#[tokio::test]
+async fn test_local_storage() {
+    let root = std::env::temp_dir().join(format!("ftp-rustified-local-{}", std::process::id()));
+    create_dir_all(&root).await.unwrap();
+    let storage = LocalStorage::new(&root);
+
+    storage.mkdir(Path::new("/docs/2024")).await.unwrap();
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), false, false).await.unwrap();
+    writer.write(b"Hello").await.unwrap();
+    assert_eq!(writer.finish().await.unwrap(), 5);
+
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), true, false).await.unwrap();
+    writer.write(b", World!").await.unwrap();
+    assert_eq!(writer.finish().await.unwrap(), 13);
+    assert_eq!(storage.open_write(Path::new("/docs/a.txt"), false, true).await.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+    let mut content = String::new();
+    let mut reader = storage.open_read(Path::new("/docs/a.txt"), 7).await.unwrap();
+    tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut content).await.unwrap();
+    assert_eq!(content, "World!");
+
+    let mut names: Vec<String> = storage.list(Path::new("/docs")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    assert_eq!(names, vec!["2024", "a.txt"]);
+
+    storage.rename(Path::new("/docs/a.txt"), Path::new("/docs/2024/b.txt")).await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/docs/2024/b.txt")).await.unwrap().size, 13);
+    assert!(storage.metadata(Path::new("/docs/a.txt")).await.is_err());
+
+    // neither parent directories nor symbolic links can lead outside of the root
+    assert_eq!(storage.metadata(Path::new("/../../etc/passwd")).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+    assert_eq!(storage.list(Path::new("/etc")).await.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+    assert!(storage.remove(Path::new("/")).await.is_err());
+    storage.remove(Path::new("/docs")).await.unwrap();
+    assert!(storage.metadata(Path::new("/docs")).await.is_err());
+
+    remove_dir_all(root).await.unwrap();
+}
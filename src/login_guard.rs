@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::ftp_config::LoginLimits;
+
+/// Failed logins of a username or source IP
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Failed logins shared between every connection of the server, used to delay the replies to
+/// the failed logins and to temporarily ban the usernames and source IPs with too many failures
+pub struct LoginGuard {
+    limits: LoginLimits,
+    users: Mutex<HashMap<String, Failures>>,
+    ips: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl LoginGuard {
+    pub fn new(limits: LoginLimits) -> Self {
+        LoginGuard {
+            limits,
+            users: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> &LoginLimits {
+        &self.limits
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        Self::is_banned(&self.ips, &ip)
+    }
+
+    pub fn is_user_banned(&self, username: &str) -> bool {
+        Self::is_banned(&self.users, username)
+    }
+
+    /// Recording a failed login, returning the delay to wait before replying to it
+    pub fn record_failure(&self, ip: IpAddr, username: &str, connection_failures: u32) -> Duration {
+        let window = Duration::from_secs(self.limits.failure_window_secs);
+        let ban = Duration::from_secs(self.limits.ban_secs);
+        let user_failures = Self::add_failure(&self.users, username.to_string(), self.limits.max_failures_per_user, window, ban);
+        let ip_failures = Self::add_failure(&self.ips, ip, self.limits.max_failures_per_ip, window, ban);
+
+        let failures = user_failures.max(ip_failures).max(connection_failures);
+        let delay = self.limits.base_delay_ms.saturating_mul(1 << failures.saturating_sub(1).min(32));
+        Duration::from_millis(delay.min(self.limits.max_delay_ms))
+    }
+
+    /// Forgetting the failed logins of the username, the ones of the IP are kept so that a
+    /// valid account can't be used to keep guessing the passwords of the others
+    pub fn record_success(&self, username: &str) {
+        self.users.lock().unwrap().remove(username);
+    }
+
+    fn is_banned<K, Q>(map: &Mutex<HashMap<K, Failures>>, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+        Q: std::hash::Hash + Eq + ?Sized,
+    {
+        let map = map.lock().unwrap();
+        map.get(key)
+            .and_then(|failures| failures.banned_until)
+            .is_some_and(|banned_until| banned_until > Instant::now())
+    }
+
+    fn add_failure<K: std::hash::Hash + Eq>(map: &Mutex<HashMap<K, Failures>>, key: K, max_failures: u32, window: Duration, ban: Duration) -> u32 {
+        let now = Instant::now();
+        let mut map = map.lock().unwrap();
+
+        // forgetting the failures outside of the window, unless they led to a ban still in effect
+        map.retain(|_, failures| {
+            now.duration_since(failures.last_failure) < window
+                || failures.banned_until.is_some_and(|banned_until| banned_until > now)
+        });
+
+        let failures = map.entry(key).or_insert(Failures { count: 0, last_failure: now, banned_until: None });
+        if failures.banned_until.is_some_and(|banned_until| banned_until <= now) {
+            failures.count = 0;
+            failures.banned_until = None;
+        }
+
+        failures.count += 1;
+        failures.last_failure = now;
+        if max_failures > 0 && failures.count >= max_failures {
+            failures.banned_until = Some(now + ban);
+        }
+        failures.count
+    }
+}
+
+#[test]
+fn test_login_guard() {
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let guard = LoginGuard::new(LoginLimits {
+        max_failures_per_user: 3,
+        max_failures_per_ip: 5,
+        base_delay_ms: 100,
+        max_delay_ms: 300,
+        ..LoginLimits::default()
+    });
+
+    assert_eq!(guard.record_failure(ip, "user1", 1), Duration::from_millis(100));
+    assert_eq!(guard.record_failure(ip, "user1", 2), Duration::from_millis(200));
+    assert!(!guard.is_user_banned("user1"));
+    assert_eq!(guard.record_failure(ip, "user1", 3), Duration::from_millis(300));
+    assert!(guard.is_user_banned("user1"));
+    assert!(!guard.is_user_banned("user2"));
+    assert!(!guard.is_ip_banned(ip));
+
+    guard.record_failure(ip, "user2", 1);
+    guard.record_success("user2");
+    assert!(!guard.is_user_banned("user2"));
+    guard.record_failure(ip, "user2", 1);
+    assert!(guard.is_ip_banned(ip));
+    assert!(!guard.is_ip_banned("10.0.0.2".parse().unwrap()));
+}
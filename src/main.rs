@@ -12,6 +12,25 @@ mod ftp_response;
 mod ftp_response_code;
 mod error;
 mod client;
+mod auth;
+mod permissions;
+mod ftp_group;
+mod mount;
+mod login_guard;
+mod ip_filter;
+mod totp;
+mod storage;
+mod local_storage;
+mod memory_storage;
+mod s3_storage;
+mod archive_storage;
+mod quota;
+mod upload_policy;
+mod filename_policy;
+mod versioning;
+mod trash;
+mod checksum;
+mod encryption;
 
 use std::path::PathBuf;
 use dotenv::dotenv;
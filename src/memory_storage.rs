@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+use crate::utils::normalize_path;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir { modified: SystemTime },
+    File { data: Vec<u8>, modified: SystemTime },
+}
+
+impl Node {
+    fn metadata(&self) -> FileMetadata {
+        match *self {
+            Node::Dir { modified } => FileMetadata::dir(modified),
+            Node::File { ref data, modified } => FileMetadata::file(data.len() as u64, modified),
+        }
+    }
+}
+
+type Nodes = BTreeMap<PathBuf, Node>;
+
+/// Storage backend keeping the whole tree in memory, lost when the server stops.
+/// Useful for tests and ephemeral servers.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    nodes: Arc<Mutex<Nodes>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::Dir { modified: SystemTime::now() });
+        MemoryStorage { nodes: Arc::new(Mutex::new(nodes)) }
+    }
+
+    fn nodes(&self) -> MutexGuard<'_, Nodes> {
+        self.nodes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+/// Function to check that the parent directory of a path exists
+fn check_parent(nodes: &Nodes, path: &Path) -> io::Result<()> {
+    match path.parent().and_then(|parent| nodes.get(parent)) {
+        Some(Node::Dir { .. }) => Ok(()),
+        Some(Node::File { .. }) => Err(io::ErrorKind::NotADirectory.into()),
+        None => Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+/// Function to get the paths of a node and everything below it
+fn subtree(nodes: &Nodes, path: &Path) -> Vec<PathBuf> {
+    nodes.range(path.to_path_buf()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(path))
+        .cloned()
+        .collect()
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let path = normalize_path(path);
+        self.nodes().get(&path).map(Node::metadata).ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        let path = normalize_path(path);
+        let nodes = self.nodes();
+        match nodes.get(&path) {
+            Some(Node::Dir { .. }) => (),
+            Some(Node::File { .. }) => return Err(io::ErrorKind::NotADirectory.into()),
+            None => return Err(io::ErrorKind::NotFound.into())
+        }
+
+        Ok(subtree(&nodes, &path).into_iter()
+            .filter(|key| key.parent() == Some(path.as_path()))
+            .filter_map(|key| Some(FileEntry {
+                name: key.file_name()?.to_string_lossy().into_owned(),
+                metadata: nodes.get(&key)?.metadata(),
+            }))
+            .collect())
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let path = normalize_path(path);
+        match self.nodes().get(&path) {
+            Some(Node::File { data, .. }) => {
+                let start = (offset as usize).min(data.len());
+                Ok(Box::new(Cursor::new(data[start..].to_vec())))
+            },
+            Some(Node::Dir { .. }) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        let path = normalize_path(path);
+        let mut nodes = self.nodes();
+        check_parent(&nodes, &path)?;
+        if create_new && nodes.contains_key(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+
+        let data = match nodes.remove(&path) {
+            Some(Node::File { data, .. }) if append => data,
+            Some(node @ Node::Dir { .. }) => {
+                nodes.insert(path, node);
+                return Err(io::ErrorKind::IsADirectory.into());
+            },
+            _ => vec![]
+        };
+        nodes.insert(path.clone(), Node::File { data, modified: SystemTime::now() });
+        Ok(Box::new(MemoryFileWriter { storage: self.clone(), path }))
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        let path = normalize_path(path);
+        let mut nodes = self.nodes();
+
+        let mut ancestors: Vec<&Path> = path.ancestors().collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            match nodes.get(ancestor) {
+                Some(Node::Dir { .. }) => (),
+                Some(Node::File { .. }) => return Err(io::ErrorKind::NotADirectory.into()),
+                None => { nodes.insert(ancestor.to_path_buf(), Node::Dir { modified: SystemTime::now() }); }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = normalize_path(path);
+        if path == Path::new("/") {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+
+        let mut nodes = self.nodes();
+        if !nodes.contains_key(&path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        for key in subtree(&nodes, &path) {
+            nodes.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize_path(from), normalize_path(to));
+        if from == Path::new("/") || to.starts_with(&from) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let mut nodes = self.nodes();
+        if !nodes.contains_key(&from) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        check_parent(&nodes, &to)?;
+
+        for key in subtree(&nodes, &to) {
+            nodes.remove(&key);
+        }
+        for key in subtree(&nodes, &from) {
+            if let Some(node) = nodes.remove(&key) {
+                let rest = key.strip_prefix(&from).unwrap_or(Path::new(""));
+                nodes.insert(if rest.as_os_str().is_empty() { to.clone() } else { to.join(rest) }, node);
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let path = normalize_path(path);
+        match self.nodes().get_mut(&path) {
+            Some(Node::Dir { modified: time }) | Some(Node::File { modified: time, .. }) => {
+                *time = modified;
+                Ok(())
+            },
+            None => Err(io::ErrorKind::NotFound.into())
+        }
+    }
+}
+
+/// Upload appended to the file of the storage as the data arrives
+struct MemoryFileWriter {
+    storage: MemoryStorage,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl FileWriter for MemoryFileWriter {
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self.storage.nodes().get_mut(&self.path) {
+            Some(Node::File { data, modified }) => {
+                data.extend_from_slice(chunk);
+                *modified = SystemTime::now();
+                Ok(())
+            },
+            // the file was removed or replaced by a directory during the upload
+            _ => Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        Ok(self.storage.metadata(&self.path).await?.size)
+    }
+
+    /// Interrupted uploads are kept as partial files
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_memory_storage() {
+    use tokio::io::AsyncReadExt;
+
+    let storage = MemoryStorage::new();
+    storage.mkdir(Path::new("/docs/2024")).await.unwrap();
+    assert!(storage.metadata(Path::new("/docs")).await.unwrap().is_dir);
+
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), false, false).await.unwrap();
+    writer.write(b"Hello").await.unwrap();
+    assert_eq!(writer.finish().await.unwrap(), 5);
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), true, false).await.unwrap();
+    writer.write(b", World!").await.unwrap();
+    assert_eq!(writer.finish().await.unwrap(), 13);
+
+    let mut content = String::new();
+    storage.open_read(Path::new("/docs/a.txt"), 7).await.unwrap().read_to_string(&mut content).await.unwrap();
+    assert_eq!(content, "World!");
+
+    assert_eq!(storage.open_write(Path::new("/missing/a.txt"), false, false).await.err().unwrap().kind(), io::ErrorKind::NotFound);
+    assert_eq!(storage.open_write(Path::new("/docs"), false, false).await.err().unwrap().kind(), io::ErrorKind::IsADirectory);
+    assert_eq!(storage.mkdir(Path::new("/docs/a.txt/b")).await.unwrap_err().kind(), io::ErrorKind::NotADirectory);
+
+    let mut names: Vec<String> = storage.list(Path::new("/docs")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    names.sort();
+    assert_eq!(names, vec!["2024", "a.txt"]);
+
+    storage.rename(Path::new("/docs"), Path::new("/archive")).await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/archive/a.txt")).await.unwrap().size, 13);
+    assert!(storage.metadata(Path::new("/archive/2024")).await.unwrap().is_dir);
+    assert!(storage.metadata(Path::new("/docs")).await.is_err());
+
+    let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    storage.set_mtime(Path::new("/archive/a.txt"), modified).await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/archive/a.txt")).await.unwrap().modified, modified);
+
+    assert!(storage.remove(Path::new("/")).await.is_err());
+    storage.remove(Path::new("/archive")).await.unwrap();
+    assert!(storage.list(Path::new("/")).await.unwrap().is_empty());
+}
@@ -0,0 +1,238 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use crate::encryption::EncryptionConfig;
+use crate::ftp_config::StorageConfig;
+use crate::quota::Quota;
+use crate::versioning::OverwritePolicy;
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+
+/// Host directory, or other storage, mounted at a virtual path of the users' view
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mount {
+    /// Virtual path of the folder, e.g. `/shared/finance`
+    pub path: PathBuf,
+    /// Host directory, relative to the server's root directory
+    #[serde(default)]
+    pub source: PathBuf,
+    /// Storage mounted instead of the source directory, e.g. an S3 bucket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    /// Refusing uploads and changes in the folder, whatever the permissions of the users
+    #[serde(default)]
+    pub read_only: bool,
+    /// Limits of the files of the folder, shared by all the users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>,
+    /// What happens to the files replaced by uploads, the server's `overwrite` policy by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overwrite: Option<OverwritePolicy>,
+    /// Encrypting the content of the files of the folder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// Storage mounted at a normalized virtual path of a session
+#[derive(Clone)]
+pub struct MountPoint {
+    pub path: PathBuf,
+    pub storage: Arc<dyn StorageBackend>,
+}
+
+/// Function to find the most specific mount containing the virtual path,
+/// returning it along with the path inside of it
+pub fn find_mount<'a>(mounts: &'a [MountPoint], path: &Path) -> Option<(&'a MountPoint, PathBuf)> {
+    mounts.iter()
+        .filter_map(|mount| path.strip_prefix(&mount.path).ok().map(|rest| (mount, Path::new("/").join(rest))))
+        .max_by_key(|(mount, _)| mount.path.components().count())
+}
+
+/// Storage of a session, made of the home directory and the storages mounted in it.
+/// Mount points and their parents show up as directories even when the home lacks them,
+/// and can't be removed or renamed.
+pub struct MountedStorage {
+    root: Arc<dyn StorageBackend>,
+    mounts: Vec<MountPoint>,
+}
+
+impl MountedStorage {
+    pub fn new(root: Arc<dyn StorageBackend>, mounts: Vec<MountPoint>) -> Self {
+        MountedStorage { root, mounts }
+    }
+
+    /// Getting the storage holding a virtual path, along with the path inside of it
+    fn route(&self, path: &Path) -> (&dyn StorageBackend, PathBuf) {
+        match find_mount(&self.mounts, path) {
+            Some((mount, rest)) => (mount.storage.as_ref(), rest),
+            None => (self.root.as_ref(), path.to_path_buf())
+        }
+    }
+
+    /// Checking if the path is a mount point or one of its parents, other than the root
+    /// which is the home directory itself
+    fn holds_mount(&self, path: &Path) -> bool {
+        path != Path::new("/") && self.mounts.iter().any(|mount| mount.path.starts_with(path))
+    }
+}
+
+fn mount_point_error(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is a mount point", path.display()))
+}
+
+#[async_trait]
+impl StorageBackend for MountedStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let (storage, inner) = self.route(path);
+        match storage.metadata(&inner).await {
+            Err(_) if self.holds_mount(path) => Ok(FileMetadata::dir(SystemTime::now())),
+            metadata => metadata
+        }
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        let (storage, inner) = self.route(path);
+        let mut entries = match storage.list(&inner).await {
+            Err(_) if self.holds_mount(path) => vec![],
+            entries => entries?
+        };
+
+        // showing the mounts and their parents placed right in this directory
+        for mount in &self.mounts {
+            let name = match mount.path.strip_prefix(path).ok().and_then(|rest| rest.iter().next()) {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue
+            };
+            let metadata = self.metadata(&path.join(&name)).await
+                .unwrap_or_else(|_| FileMetadata::dir(SystemTime::now()));
+
+            entries.retain(|entry| entry.name != name);
+            entries.push(FileEntry { name, metadata });
+        }
+        Ok(entries)
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let (storage, inner) = self.route(path);
+        storage.open_read(&inner, offset).await
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        if self.holds_mount(path) {
+            return Err(mount_point_error(path));
+        }
+        let (storage, inner) = self.route(path);
+        storage.open_write(&inner, append, create_new).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        let (storage, inner) = self.route(path);
+        storage.mkdir(&inner).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        if self.holds_mount(path) {
+            return Err(mount_point_error(path));
+        }
+        let (storage, inner) = self.route(path);
+        storage.remove(&inner).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        if self.holds_mount(path) {
+            return Err(mount_point_error(path));
+        }
+        let (storage, inner) = self.route(path);
+        storage.delete(&inner).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.holds_mount(from) || self.holds_mount(to) {
+            return Err(mount_point_error(from));
+        }
+
+        let (storage, from) = self.route(from);
+        let (to_storage, to) = self.route(to);
+        if !std::ptr::addr_eq(storage, to_storage) {
+            return Err(io::ErrorKind::CrossesDevices.into());
+        }
+        storage.rename(&from, &to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let (storage, inner) = self.route(path);
+        storage.set_mtime(&inner, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let (storage, inner) = self.route(path);
+        storage.set_attribute(&inner, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        let (storage, inner) = self.route(path);
+        storage.available_space(&inner).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        let (storage, inner) = self.route(path);
+        storage.check_overwrite(&inner).await
+    }
+}
+
+#[test]
+fn test_find_mount() {
+    use crate::local_storage::LocalStorage;
+
+    let mounts = vec![
+        MountPoint { path: PathBuf::from("/shared"), storage: Arc::new(LocalStorage::new("/srv/shared")) },
+        MountPoint { path: PathBuf::from("/shared/finance"), storage: Arc::new(LocalStorage::new("/srv/finance")) },
+    ];
+
+    let (mount, rest) = find_mount(&mounts, Path::new("/shared/finance/q1/report.csv")).unwrap();
+    assert_eq!(mount.path, PathBuf::from("/shared/finance"));
+    assert_eq!(rest, PathBuf::from("/q1/report.csv"));
+    assert_eq!(find_mount(&mounts, Path::new("/shared/hr")).unwrap().0.path, PathBuf::from("/shared"));
+    assert_eq!(find_mount(&mounts, Path::new("/shared")).unwrap().1, PathBuf::from("/"));
+    assert!(find_mount(&mounts, Path::new("/sharedfiles")).is_none());
+}
+
+#[tokio::test]
+async fn test_mounted_storage() {
+    use crate::local_storage::LocalStorage;
+
+    let base = std::env::temp_dir().join(format!("ftp-rustified-mounts-{}", std::process::id()));
+    tokio::fs::create_dir_all(base.join("home")).await.unwrap();
+    tokio::fs::create_dir_all(base.join("finance")).await.unwrap();
+    tokio::fs::create_dir_all(base.join("hr")).await.unwrap();
+    tokio::fs::write(base.join("finance/report.csv"), b"q1").await.unwrap();
+
+    let storage = MountedStorage::new(
+        Arc::new(LocalStorage::new(base.join("home"))),
+        vec![
+            MountPoint { path: PathBuf::from("/shared/finance"), storage: Arc::new(LocalStorage::new(base.join("finance"))) },
+            MountPoint { path: PathBuf::from("/shared/hr"), storage: Arc::new(LocalStorage::new(base.join("hr"))) },
+        ]
+    );
+
+    let names: Vec<String> = storage.list(Path::new("/")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, vec!["shared"]);
+    assert!(storage.metadata(Path::new("/shared")).await.unwrap().is_dir);
+    assert_eq!(storage.metadata(Path::new("/shared/finance/report.csv")).await.unwrap().size, 2);
+    assert_eq!(storage.list(Path::new("/shared/finance")).await.unwrap()[0].name, "report.csv");
+    assert_eq!(storage.list(Path::new("/shared")).await.unwrap().len(), 2);
+
+    assert!(storage.holds_mount(Path::new("/shared")) && !storage.holds_mount(Path::new("/")) && !storage.holds_mount(Path::new("/shared/finance/report.csv")));
+    assert!(storage.remove(Path::new("/shared")).await.is_err());
+    assert!(storage.remove(Path::new("/shared/finance")).await.is_err());
+    // files only move inside of a mount
+    assert_eq!(storage.rename(Path::new("/shared/finance/report.csv"), Path::new("/report.csv")).await.unwrap_err().kind(), io::ErrorKind::CrossesDevices);
+    assert_eq!(storage.rename(Path::new("/shared/finance/report.csv"), Path::new("/shared/hr/report.csv")).await.unwrap_err().kind(), io::ErrorKind::CrossesDevices);
+    storage.rename(Path::new("/shared/finance/report.csv"), Path::new("/shared/finance/q1.csv")).await.unwrap();
+    assert!(storage.metadata(Path::new("/shared/finance/report.csv")).await.is_err());
+    storage.remove(Path::new("/shared/finance/q1.csv")).await.unwrap();
+
+    tokio::fs::remove_dir_all(base).await.unwrap();
+}
@@ -0,0 +1,106 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+/// Single operation a user can be allowed to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    List,
+    Read,
+    Write,
+    Delete,
+    /// Renaming files and directories, taking effect once `RNFR` and `RNTO` are supported
+    Rename,
+    Mkdir,
+    Rmdir,
+    /// Changing the mode of files, taking effect once `SITE CHMOD` is supported
+    Chmod,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Permission::List,
+        Permission::Read,
+        Permission::Write,
+        Permission::Delete,
+        Permission::Rename,
+        Permission::Mkdir,
+        Permission::Rmdir,
+        Permission::Chmod,
+    ];
+}
+
+/// Set of permissions granted to a user, serialized as a list e.g. `["list", "read"]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Permissions(Vec<Permission>);
+
+impl Permissions {
+    pub fn new(permissions: Vec<Permission>) -> Self {
+        Permissions(permissions)
+    }
+
+    pub fn all() -> Self {
+        Permissions(Permission::ALL.to_vec())
+    }
+
+    pub fn read_only() -> Self {
+        Permissions(vec![Permission::List, Permission::Read])
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.0.contains(&permission)
+    }
+
+    pub fn union(&self, other: &Permissions) -> Self {
+        let mut permissions = self.0.clone();
+        permissions.extend(other.0.iter().filter(|permission| !self.0.contains(permission)));
+        Permissions(permissions)
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions::all()
+    }
+}
+
+/// Permissions overriding the user's ones for a path (relative to the home directory) and its content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathPermissions {
+    pub path: PathBuf,
+    pub permissions: Permissions,
+}
+
+/// Function to get the permissions applying to a path, from the most specific matching path rule
+pub fn permissions_for<'a>(permissions: &'a Permissions, path_permissions: &'a [PathPermissions], path: &Path) -> &'a Permissions {
+    let path = Path::new("/").join(path);
+    path_permissions.iter()
+        .filter(|rule| path.starts_with(Path::new("/").join(&rule.path)))
+        .max_by_key(|rule| rule.path.components().count())
+        .map_or(permissions, |rule| &rule.permissions)
+}
+
+#[test]
+fn test_permissions() {
+    let permissions: Permissions = serde_json::from_str(r#"["list","read"]"#).unwrap();
+    assert!(permissions.allows(Permission::List));
+    assert!(permissions.allows(Permission::Read));
+    assert!(!permissions.allows(Permission::Write));
+    assert!(Permissions::default().allows(Permission::Chmod));
+    let permissions: Permissions = serde_json::from_str(r#"["list","rename","chmod"]"#).unwrap();
+    assert!(permissions.allows(Permission::Rename) && !permissions.allows(Permission::Delete));
+}
+
+#[test]
+fn test_permissions_for() {
+    let permissions = Permissions::read_only();
+    let path_permissions: Vec<PathPermissions> = serde_json::from_str(
+        r#"[{"path":"/upload","permissions":["write"]},{"path":"upload/shared","permissions":["list","read","write"]}]"#
+    ).unwrap();
+
+    assert_eq!(permissions_for(&permissions, &path_permissions, Path::new("/docs/a.txt")), &permissions);
+    assert_eq!(permissions_for(&permissions, &path_permissions, Path::new("/uploads")), &permissions);
+    assert!(!permissions_for(&permissions, &path_permissions, Path::new("/upload/a.txt")).allows(Permission::Read));
+    assert!(permissions_for(&permissions, &path_permissions, Path::new("upload/shared/a.txt")).allows(Permission::Read));
+}
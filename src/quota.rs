@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+
+/// Time the usage of a storage is trusted for before it's measured again, as it also changes
+/// outside of the server
+const USAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Limits of the size and number of the files of a user or a mounted folder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+}
+
+/// Size and number of the files of a tree
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Function to sum the files of a directory of a storage and its subdirectories, the deleted
+/// files and the previous versions of the files included
+pub async fn usage(storage: &dyn StorageBackend, path: &Path) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in storage.list(&dir).await? {
+            if entry.metadata.is_dir {
+                dirs.push(dir.join(&entry.name));
+            } else {
+                usage.bytes += entry.metadata.size;
+                usage.files += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Usages of the storages with a quota by their location, shared between every connection of the
+/// server so that the sessions of a user, and the users of a mount, share the same quota
+#[derive(Default)]
+pub struct UsageRegistry {
+    usages: Mutex<HashMap<String, Arc<SharedUsage>>>,
+}
+
+impl UsageRegistry {
+    pub fn new() -> Self {
+        UsageRegistry::default()
+    }
+
+    pub fn get(&self, location: &str) -> Arc<SharedUsage> {
+        self.usages.lock().unwrap().entry(location.to_string()).or_default().clone()
+    }
+}
+
+/// Usage of a storage with a quota, along with the bytes and files the uploads in progress
+/// reserved as their data came in
+#[derive(Default)]
+pub struct SharedUsage {
+    state: Mutex<UsageState>,
+}
+
+#[derive(Default)]
+struct UsageState {
+    /// Usage of the storage along with the time it was measured
+    measured: Option<(Usage, Instant)>,
+    /// Whether the storage changed since it was measured, otherwise than by an upload
+    outdated: bool,
+    reserved: Usage,
+    uploads: u64,
+}
+
+impl UsageState {
+    fn total(&self, measured: Usage) -> Usage {
+        Usage { bytes: measured.bytes + self.reserved.bytes, files: measured.files + self.reserved.files }
+    }
+}
+
+/// Storage enforcing a quota on the files of another storage. The usage is measured at the first
+/// upload and kept up to date by the uploads, and an upload fails as soon as it writes past the
+/// quota, the uploads in progress counting with what they already wrote. The other storage is
+/// expected to write atomically, so that a refused upload leaves the file it replaces untouched.
+pub struct QuotaStorage {
+    inner: Arc<dyn StorageBackend>,
+    quota: Quota,
+    usage: Arc<SharedUsage>,
+}
+
+impl QuotaStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, quota: Quota, usage: Arc<SharedUsage>) -> Self {
+        QuotaStorage { inner, quota, usage }
+    }
+
+    /// Measuring the usage of the storage again once it's outdated. It isn't measured during
+    /// uploads, whose temporary files would be counted along with their reservations.
+    async fn measure_usage(&self) -> io::Result<()> {
+        {
+            let state = self.usage.state.lock().unwrap();
+            match state.measured {
+                Some(_) if state.uploads > 0 => return Ok(()),
+                Some((_, measured)) if !state.outdated && measured.elapsed() < USAGE_TTL => return Ok(()),
+                _ => ()
+            }
+        }
+        let usage = usage(self.inner.as_ref(), Path::new("/")).await?;
+        let mut state = self.usage.state.lock().unwrap();
+        state.measured = Some((usage, Instant::now()));
+        state.outdated = false;
+        Ok(())
+    }
+
+    fn forget_usage(&self) {
+        self.usage.state.lock().unwrap().outdated = true;
+    }
+}
+
+#[async_trait]
+impl StorageBackend for QuotaStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(path).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(path, offset).await
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        self.measure_usage().await?;
+        let existing = self.inner.metadata(path).await.ok().filter(|metadata| !metadata.is_dir);
+
+        // the new file is reserved until the upload ends
+        let reservation = {
+            let mut state = self.usage.state.lock().unwrap();
+            let usage = state.total(state.measured.map(|(usage, _)| usage).unwrap_or_default());
+            if existing.is_none() && self.quota.max_files.is_some_and(|max_files| usage.files >= max_files) {
+                return Err(io::Error::new(io::ErrorKind::QuotaExceeded, "File quota exceeded"));
+            }
+            state.reserved.files += existing.is_none() as u64;
+            state.uploads += 1;
+            Reservation { usage: self.usage.clone(), bytes: 0, new_file: existing.is_none() }
+        };
+
+        // the replaced file doesn't count
+        let replaced = match existing {
+            Some(ref metadata) if !append => metadata.size,
+            _ => 0
+        };
+        let inner = self.inner.open_write(path, append, create_new).await?;
+        Ok(Box::new(QuotaFileWriter { inner, max_bytes: self.quota.max_bytes, written: 0, replaced, reservation }))
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.forget_usage();
+        self.inner.remove(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.forget_usage();
+        self.inner.delete(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.forget_usage();
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(path, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(path, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(path).await
+    }
+}
+
+/// Bytes and file an upload in progress holds in the usage of its storage, given back when the
+/// upload ends, however it ends
+struct Reservation {
+    usage: Arc<SharedUsage>,
+    bytes: u64,
+    new_file: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.usage.state.lock().unwrap();
+        state.reserved.bytes -= self.bytes;
+        state.reserved.files -= self.new_file as u64;
+        state.uploads -= 1;
+    }
+}
+
+/// Upload reserving its bytes in the usage of its storage as they are written, beyond the size of
+/// the file it replaces, and adding the file to the usage once complete
+struct QuotaFileWriter {
+    inner: Box<dyn FileWriter>,
+    max_bytes: Option<u64>,
+    written: u64,
+    /// Size of the file replaced by the upload
+    replaced: u64,
+    reservation: Reservation,
+}
+
+#[async_trait]
+impl FileWriter for QuotaFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let bytes = (self.written + data.len() as u64).saturating_sub(self.replaced);
+        {
+            let mut state = self.reservation.usage.state.lock().unwrap();
+            let usage = state.total(state.measured.map(|(usage, _)| usage).unwrap_or_default());
+            let needed = bytes - self.reservation.bytes;
+            if self.max_bytes.is_some_and(|max_bytes| usage.bytes + needed > max_bytes) {
+                return Err(io::Error::new(io::ErrorKind::QuotaExceeded, "Not enough storage left for the file"));
+            }
+            state.reserved.bytes += needed;
+            self.reservation.bytes = bytes;
+        }
+        self.inner.write(data).await?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        let size = self.inner.finish().await?;
+        let mut state = self.reservation.usage.state.lock().unwrap();
+        if let Some((ref mut usage, _)) = state.measured {
+            usage.bytes = usage.bytes.saturating_sub(self.replaced) + self.written;
+            usage.files += self.reservation.new_file as u64;
+        }
+        Ok(size)
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+/// Upload failing with an error of the given kind once it writes more than `remaining` bytes
+pub struct LimitedFileWriter {
+    inner: Box<dyn FileWriter>,
+    remaining: u64,
+    kind: io::ErrorKind,
+}
+
+impl LimitedFileWriter {
+    pub fn new(inner: Box<dyn FileWriter>, remaining: u64, kind: io::ErrorKind) -> Self {
+        LimitedFileWriter { inner, remaining, kind }
+    }
+}
+
+#[async_trait]
+impl FileWriter for LimitedFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.remaining {
+            return Err(io::Error::new(self.kind, "Not enough storage left for the file"));
+        }
+        self.remaining -= data.len() as u64;
+        self.inner.write(data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        self.inner.finish().await
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+#[tokio::test]
+async fn test_quota_storage() {
+    use crate::memory_storage::MemoryStorage;
+
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let usages = UsageRegistry::new();
+    let storage = QuotaStorage::new(inner.clone(), Quota { max_bytes: Some(10), max_files: Some(2) }, usages.get("/"));
+    storage.mkdir(Path::new("/docs")).await.unwrap();
+
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), false, false).await.unwrap();
+    writer.write(b"Hello").await.unwrap();
+    writer.finish().await.unwrap();
+    assert_eq!(usage(inner.as_ref(), Path::new("/")).await.unwrap(), Usage { bytes: 5, files: 1 });
+
+    // the upload fails once it goes over the 5 bytes left
+    let mut writer = storage.open_write(Path::new("/b.txt"), false, false).await.unwrap();
+    writer.write(b"World").await.unwrap();
+    assert_eq!(writer.write(b"!").await.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    writer.abort().await.unwrap();
+    storage.remove(Path::new("/b.txt")).await.unwrap();
+
+    // replacing a file frees its size, and a third file is refused
+    let mut writer = storage.open_write(Path::new("/docs/a.txt"), false, false).await.unwrap();
+    writer.write(b"0123456789").await.unwrap();
+    assert_eq!(writer.write(b"!").await.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    writer.abort().await.unwrap();
+    storage.open_write(Path::new("/c.txt"), false, false).await.unwrap().finish().await.unwrap();
+    assert_eq!(storage.open_write(Path::new("/d.txt"), false, false).await.err().unwrap().kind(), io::ErrorKind::QuotaExceeded);
+
+    // the sessions of a location share its usage, the uploads in progress included
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let sessions = [0, 1].map(|_| QuotaStorage::new(inner.clone(), Quota { max_bytes: Some(10), max_files: Some(2) }, usages.get("/shared")));
+    let mut first = sessions[0].open_write(Path::new("/a.txt"), false, false).await.unwrap();
+    let mut second = sessions[1].open_write(Path::new("/b.txt"), false, false).await.unwrap();
+    first.write(b"Hello").await.unwrap();
+    second.write(b"World").await.unwrap();
+    assert_eq!(first.write(b"!").await.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    assert_eq!(sessions[0].open_write(Path::new("/c.txt"), false, false).await.err().unwrap().kind(), io::ErrorKind::QuotaExceeded);
+    first.abort().await.unwrap();
+    second.finish().await.unwrap();
+    let mut writer = sessions[0].open_write(Path::new("/c.txt"), false, false).await.unwrap();
+    writer.write(b"Hello").await.unwrap();
+    assert_eq!(writer.write(b"!").await.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+}
+
+#[tokio::test]
+async fn test_quota_atomic_uploads() {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::AtomicStorage;
+
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let storage = QuotaStorage::new(Arc::new(AtomicStorage::new(inner.clone())), Quota { max_bytes: Some(10), max_files: Some(1) }, Arc::default());
+    let mut writer = storage.open_write(Path::new("/a.txt"), false, false).await.unwrap();
+    writer.write(b"Hello").await.unwrap();
+    writer.finish().await.unwrap();
+
+    // a file is replaced at the file quota, its bytes counted once
+    let mut writer = storage.open_write(Path::new("/a.txt"), false, false).await.unwrap();
+    writer.write(b"0123456789").await.unwrap();
+    writer.finish().await.unwrap();
+    assert_eq!(usage(inner.as_ref(), Path::new("/")).await.unwrap(), Usage { bytes: 10, files: 1 });
+
+    // a refused upload leaves the replaced file untouched
+    let mut writer = storage.open_write(Path::new("/a.txt"), false, false).await.unwrap();
+    writer.write(b"0123456789").await.unwrap();
+    assert_eq!(writer.write(b"!").await.unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+    writer.abort().await.unwrap();
+    assert_eq!(inner.metadata(Path::new("/a.txt")).await.unwrap().size, 10);
+    assert_eq!(inner.list(Path::new("/")).await.unwrap().len(), 1);
+
+    // the deleted files and the previous versions count too
+    for path in ["/.trash/b.txt", "/docs/.versions/c.txt/20240131-093000-123"] {
+        inner.mkdir(Path::new(path).parent().unwrap()).await.unwrap();
+        inner.open_write(Path::new(path), false, false).await.unwrap().finish().await.unwrap();
+    }
+    assert_eq!(usage(inner.as_ref(), Path::new("/")).await.unwrap(), Usage { bytes: 10, files: 3 });
+}
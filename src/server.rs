@@ -1,24 +1,46 @@
 use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::Utc;
 use dotenv::dotenv;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
+use crate::auth::{new_authenticator, Authenticator};
 use crate::client::Client;
 use crate::client_command::Command;
 use crate::ftp_config::FtpConfig;
 use crate::ftp_response::Response;
 use crate::ftp_response_code::ResponseCode;
+use crate::login_guard::LoginGuard;
+use crate::quota::UsageRegistry;
+use crate::storage::StorageRoot;
+use crate::trash::{TrashRegistry, PURGE_INTERVAL};
+#[cfg(test)]
+use crate::ftp_config::{AuthConfig, StorageConfig};
+#[cfg(test)]
+use crate::ftp_user::FtpUser;
 
 pub struct Server {
-    root_dir_server: PathBuf,
-    ftp_config: FtpConfig,
+    storage_root: StorageRoot,
+    ftp_config: Arc<FtpConfig>,
+    authenticator: Arc<dyn Authenticator>,
+    login_guard: Arc<LoginGuard>,
+    trashes: Arc<TrashRegistry>,
+    usages: Arc<UsageRegistry>,
 }
 
 impl Server {
     pub fn new(root_dir_server: PathBuf, ftp_config: FtpConfig) -> Self {
+        let authenticator = new_authenticator(&ftp_config);
+        let login_guard = Arc::new(LoginGuard::new(ftp_config.login_limits.clone()));
+        let storage_root = StorageRoot::new(ftp_config.storage.as_ref(), root_dir_server);
         Server {
-            root_dir_server,
-            ftp_config,
+            storage_root,
+            ftp_config: Arc::new(ftp_config),
+            authenticator,
+            login_guard,
+            trashes: Arc::new(TrashRegistry::new()),
+            usages: Arc::new(UsageRegistry::new()),
         }
     }
 
@@ -33,18 +55,50 @@ impl Server {
 
 
         let listener = TcpListener::bind(&socket_addr).await.unwrap();
+        self.serve(listener).await;
+    }
+
+    /// Accepting the connections of a bound listener
+    pub async fn serve(&self, listener: TcpListener) {
+        // the expired items of the trashes are purged in the background, away from the logins
+        if self.ftp_config.trash.is_some_and(|trash| trash.retention_days.is_some()) {
+            let trashes = self.trashes.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PURGE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    trashes.purge().await;
+                }
+            });
+        }
 
         // corrected loop hierarchy
         loop {
             let (mut stream, addr) = listener.accept().await.unwrap();
-            let root_dir_server = self.root_dir_server.clone();
+            let storage_root = self.storage_root.clone();
             let ftp_config = self.ftp_config.clone();
+            let authenticator = self.authenticator.clone();
+            let login_guard = self.login_guard.clone();
+            let trashes = self.trashes.clone();
+            let usages = self.usages.clone();
 
             tokio::spawn(async move {
                 // let (mut reader, mut writer) = stream.split();
                 let (mut reader, mut writer) = tokio::io::split(stream);
                 let mut reader_lines = BufReader::new(reader).lines();
 
+                if !ftp_config.ip_filter.allows(addr.ip()) {
+                    let resp = Response::new(ResponseCode::ServiceNotAvailable, "Connection not allowed from your address\r\n").to_string();
+                    writer.write_all(resp.as_bytes()).await.ok();
+                    return;
+                }
+
+                if login_guard.is_ip_banned(addr.ip()) {
+                    let resp = Response::new(ResponseCode::ServiceNotAvailable, "Too many failed logins, try again later\r\n").to_string();
+                    writer.write_all(resp.as_bytes()).await.ok();
+                    return;
+                }
+
                 // NOTE: required to add \r\n after the message compulsorily for the client to be able to parse it
                 let resp = Response::new(ResponseCode::ServiceReadyForNewUser, "Welcome to the FTP Server\r\n").to_string();
                 println!("\t\tRESPONSE in run: ==> {:?}", &resp);
@@ -52,34 +106,45 @@ impl Server {
                 writer.write_all(resp.as_bytes()).await.unwrap();
                 // writer.write_all(b"220 Welcome to the FTP Server\r\n").await.unwrap();
 
-                let mut client = Client::new(writer, root_dir_server.clone(), ftp_config.clone());
+                let mut client = Client::new(writer, storage_root, ftp_config.clone(), authenticator, login_guard, trashes, usages, addr.ip());
+
+                // reading the commands until the client disconnects, the connection has to be closed
+                // or the account of the logged in user expires
                 loop {
-                    // let mut buffer = [0; 1024];
-                    // let n = reader.read(&mut buffer).await.unwrap();
-                    // if n == 0 {
-                    //     println!("No data received from client");
-                    //     break;
-                    // }
-                    // let command = String::from_utf8_lossy(&buffer[..n]).to_string();
-                    // println!("=====|INCOMING COMMAND:\n{}\n", &command);
-                    // let resp = Response::new(ResponseCode::Ok, "Hello, World!").to_string();
-                    // println!("=====|RESPONSE:\n{}\n", &resp);
-
-                    while let Ok(line) = reader_lines.next_line().await {
-                        // let command = line.unwrap();
-                        if let Some(command) = line {
-                            println!("--------inside while Reading Command");
-                            println!("|||||| RAW Command: {} ||||||||", &command);
-                            let command = command.trim().to_string();
-                            let cmd = Command::new(&command).unwrap();
-                            client = client.handle_command(cmd).await.unwrap();
-                        }
-                        // else {
-                        //     println!("-------No data received from client");
-                        //     break;
-                        // }
+                    let line = match client.expires_at() {
+                        Some(expires_at) => {
+                            let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+                            tokio::select! {
+                                line = reader_lines.next_line() => line,
+                                _ = tokio::time::sleep(remaining) => {
+                                    client.expire().await.ok();
+                                    break;
+                                }
+                            }
+                        },
+                        None => reader_lines.next_line().await
+                    };
+                    let command = match line {
+                        Ok(Some(command)) => command,
+                        _ => break
+                    };
+                    if client.expires_at().is_some_and(|expires_at| expires_at <= Utc::now()) {
+                        client.expire().await.ok();
+                        break;
+                    }
+
+                    println!("--------inside while Reading Command");
+                    println!("|||||| RAW Command: {} ||||||||", &command);
+                    let command = command.trim().to_string();
+                    let cmd = Command::new(&command).unwrap();
+                    // the future of a command is kept on the heap rather than on the stack of the task
+                    client = Box::pin(client.handle_command(cmd)).await.unwrap();
+
+                    if client.is_closed() {
+                        break;
                     }
                 }
+                println!("-------Connection closed: {}", addr);
             });
         }
     }
@@ -95,4 +160,268 @@ async fn test_server() {
     });
     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
     server_handle.abort();
-}
\ No newline at end of file
+}
+/// Config of the session tests, serving the in-memory storage
+#[cfg(test)]
+fn test_config() -> FtpConfig {
+    FtpConfig { addr: "127.0.0.1".to_string(), storage: Some(StorageConfig::Memory), ..FtpConfig::default() }
+}
+
+/// User of the session tests, whose password is their name
+#[cfg(test)]
+fn test_user(name: &str) -> FtpUser {
+    FtpUser { username: name.to_string(), password: name.to_string(), ..FtpUser::default() }
+}
+
+/// Control connection of the session tests, reading a reply line after each command
+#[cfg(test)]
+struct TestSession {
+    /// Port the server was bound to, for the other sessions
+    port: u16,
+    lines: tokio::io::Lines<BufReader<tokio::io::ReadHalf<tokio::net::TcpStream>>>,
+    writer: tokio::io::WriteHalf<tokio::net::TcpStream>,
+}
+
+#[cfg(test)]
+impl TestSession {
+    /// Starting a server with the config on a free port and connecting to it, past the welcome message
+    async fn start(config: FtpConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new(PathBuf::from("/nonexistent"), config);
+        tokio::spawn(async move {
+            server.serve(listener).await;
+        });
+        Self::connect(port).await
+    }
+
+    /// Starting a server with a single user
+    async fn with_user(user: FtpUser) -> Self {
+        Self::start(FtpConfig { users: vec![user], ..test_config() }).await
+    }
+
+    /// Opening another session on the server of a port
+    async fn connect(port: u16) -> Self {
+        let (reader, writer) = tokio::io::split(tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        let mut session = TestSession { port, lines: BufReader::new(reader).lines(), writer };
+        assert!(session.reply().await.unwrap().starts_with("220"));
+        session
+    }
+
+    /// Logging in as a user of `test_user`
+    async fn login(&mut self, name: &str) {
+        assert!(self.send(&format!("USER {}", name)).await.starts_with("331"));
+        assert!(self.send(&format!("PASS {}", name)).await.starts_with("230"));
+    }
+
+    /// Getting the next reply line, `None` once the server closed the connection
+    async fn reply(&mut self) -> Option<String> {
+        self.lines.next_line().await.unwrap()
+    }
+
+    async fn send(&mut self, command: &str) -> String {
+        self.writer.write_all(format!("{}\r\n", command).as_bytes()).await.unwrap();
+        self.reply().await.unwrap_or_default()
+    }
+
+    /// Sending the commands, checking the start of their replies
+    async fn expect(&mut self, replies: &[(&str, &str)]) {
+        for (command, code) in replies {
+            assert!(self.send(command).await.starts_with(code), "{}", command);
+        }
+    }
+
+    /// Opening a passive data connection
+    async fn passive(&mut self) -> tokio::net::TcpStream {
+        let pasv = self.send("PASV").await;
+        let numbers: Vec<u16> = pasv[pasv.find('(').unwrap() + 1..pasv.find(')').unwrap()].split(',').map(|n| n.parse().unwrap()).collect();
+        tokio::net::TcpStream::connect(("127.0.0.1", numbers[4] << 8 | numbers[5])).await.unwrap()
+    }
+
+    /// Uploading a file with `STOR`, returning the reply once the transfer is over
+    async fn store(&mut self, path: &str, data: &[u8]) -> String {
+        let mut data_connection = self.passive().await;
+        assert!(self.send(&format!("STOR {}", path)).await.starts_with("125"));
+        data_connection.write_all(data).await.unwrap();
+        data_connection.shutdown().await.unwrap();
+        drop(data_connection);
+        self.reply().await.unwrap_or_default()
+    }
+}
+
+/// Test of a whole session against a server running on the in-memory storage
+#[tokio::test]
+async fn test_memory_server() {
+    let mut session = TestSession::with_user(FtpUser { home: Some("%u".to_string()), ..test_user("user1") }).await;
+    session.login("user1").await;
+    session.expect(&[("MKD docs", "257"), ("CWD docs", "250")]).await;
+    assert!(session.store("a.txt", b"Hello, World!").await.starts_with("226"));
+    assert_eq!(session.send("SIZE /docs/a.txt").await, "213 13");
+    assert_eq!(session.send("PWD").await, "257 \"/docs\"");
+}
+
+/// Test of the logins failing on the authenticator, answered before closing the connection
+#[tokio::test]
+async fn test_authentication_unavailable() {
+    let htpasswd = AuthConfig::Htpasswd { path: PathBuf::from("/nonexistent/htpasswd"), admins: vec![] };
+    let mut session = TestSession::start(FtpConfig { auth: Some(htpasswd), ..test_config() }).await;
+    assert!(session.send("USER user1").await.starts_with("421"));
+    assert!(session.reply().await.is_none());
+
+    // the command authenticator timing out
+    let command = AuthConfig::Command { program: "sleep".to_string(), args: vec!["5".to_string()], timeout_secs: 1 };
+    let mut session = TestSession::start(FtpConfig { auth: Some(command), ..test_config() }).await;
+    session.expect(&[("USER user1", "331"), ("PASS user1", "421")]).await;
+    assert!(session.reply().await.is_none());
+}
+
+/// Test of the transfers of `MODE Z`, compressed with zlib in both directions
+#[tokio::test]
+async fn test_mode_z() {
+    use std::io::Write;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    let mut session = TestSession::with_user(test_user("user1")).await;
+    session.login("user1").await;
+    session.expect(&[("TYPE I", "200"), ("MODE B", "504"), ("MODE Z", "200"), ("OPTS MODE Z LEVEL 10", "501"), ("OPTS MODE Z LEVEL fast", "501"), ("OPTS MODE Z LEVEL 9", "200")]).await;
+
+    // the upload is stored decompressed
+    let data: Vec<u8> = (0..20000).flat_map(|i| format!("{},row\n", i).into_bytes()).collect();
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&data).unwrap();
+    assert!(session.store("log.csv", &encoder.finish().unwrap()).await.starts_with("226"));
+    assert_eq!(session.send("SIZE log.csv").await, format!("213 {}", data.len()));
+
+    // and downloaded compressed
+    let mut data_connection = session.passive().await;
+    assert!(session.send("RETR log.csv").await.starts_with("125"));
+    let mut received = vec![];
+    data_connection.read_to_end(&mut received).await.unwrap();
+    assert!(session.reply().await.unwrap().starts_with("226"));
+    assert!(received.len() < data.len());
+    let mut decompressed = vec![];
+    std::io::Read::read_to_end(&mut flate2::read::ZlibDecoder::new(&received[..]), &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+
+    // an upload which isn't zlib data is discarded
+    assert!(session.store("bad.csv", b"not compressed").await.starts_with("451"));
+    assert!(session.send("SIZE bad.csv").await.starts_with("550"));
+}
+
+/// Test of a user logging in from a network it isn't allowed from, refused like a wrong password
+#[tokio::test]
+async fn test_user_ip_filter() {
+    let ip_filter = serde_json::from_str(r#"{"allow": ["10.0.0.0/8"]}"#).unwrap();
+    let mut session = TestSession::with_user(FtpUser { ip_filter, ..test_user("partner") }).await;
+    assert!(session.send("USER partner").await.starts_with("331"));
+    assert_eq!(session.send("PASS wrong").await, "530 Invalid Password");
+    assert_eq!(session.send("PASS partner").await, "530 Invalid Password");
+}
+
+/// Test of a password ending with digits, verified once for the users without a TOTP secret
+#[tokio::test]
+async fn test_password_verified_once() {
+    let runs = std::env::temp_dir().join(format!("ftp-rustified-auth-runs-{}", std::process::id()));
+    let script = format!(r#"echo run >> {}; read user; read pass; [ "$pass" = "secret" ]"#, runs.display());
+    let command = AuthConfig::Command { program: "sh".to_string(), args: vec!["-c".to_string(), script], timeout_secs: 5 };
+    let mut session = TestSession::start(FtpConfig { auth: Some(command), ..test_config() }).await;
+    session.expect(&[("USER partner", "331"), ("PASS wrong123456", "530")]).await;
+    assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 1);
+    std::fs::remove_file(&runs).ok();
+}
+
+/// Test of a passwordless user with a TOTP secret, who still has to send a one-time code
+#[tokio::test]
+async fn test_passwordless_totp() {
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    let mut session = TestSession::with_user(FtpUser { password: String::new(), totp_secret: Some(secret.to_string()), ..test_user("kiosk") }).await;
+    session.expect(&[("USER kiosk", "332"), ("PWD", "530"), ("USER kiosk", "332"), ("ACCT 000000", "530"), ("PASS 000000", "530")]).await;
+    let code = crate::totp::code(secret, Utc::now().timestamp() as u64);
+    assert!(session.send(&format!("ACCT {}", code)).await.starts_with("230"));
+
+    // the code is accepted as the password too
+    let mut session = TestSession::connect(session.port).await;
+    session.expect(&[("USER kiosk", "332"), (&format!("PASS {}", code), "230")]).await;
+}
+
+/// Test of the directories of the trash and the previous versions, which the clients can't create
+#[tokio::test]
+async fn test_reserved_names() {
+    let mut session = TestSession::with_user(test_user("user")).await;
+    session.login("user").await;
+    session.expect(&[("MKD .trash", "553"), ("MKD docs/.versions", "553"), ("MKD docs", "257")]).await;
+    let _data_connection = session.passive().await;
+    assert!(session.send("STOR .versions/a.txt").await.starts_with("553"));
+}
+
+/// Test of `REIN` ending the session of a user so that another one can log in on the connection
+#[tokio::test]
+async fn test_reinitialize() {
+    let users = ["user1", "user2"].map(|name| FtpUser { home: Some("%u".to_string()), ..test_user(name) });
+    let mut session = TestSession::start(FtpConfig { users: users.to_vec(), ..test_config() }).await;
+    session.login("user1").await;
+    session.expect(&[("MKD docs", "257"), ("CWD docs", "250"), ("MODE Z", "200")]).await;
+
+    // the files, working directory and mode of the first user are gone
+    session.expect(&[("REIN", "220"), ("SIZE /docs", "530")]).await;
+    session.login("user2").await;
+    session.expect(&[("PWD", "257 \"/\""), ("CWD /docs", "550")]).await;
+    assert!(session.store("a.txt", b"Hello, World!").await.starts_with("226"));
+    assert_eq!(session.send("SIZE a.txt").await, "213 13");
+}
+
+/// Test of `MFMT` setting the modification time of an uploaded file
+#[tokio::test]
+async fn test_mfmt() {
+    let mut session = TestSession::with_user(test_user("user")).await;
+    session.login("user").await;
+    assert!(session.store("a.txt", b"Hello").await.starts_with("226"));
+    assert_eq!(session.send("MFMT 20240131093000 a.txt").await, "213 Modify=20240131093000; a.txt");
+    session.expect(&[("MFMT 2024013109300 a.txt", "501"), ("MFMT 20241331093000 a.txt", "501"), ("MFMT 20240131093000", "501"), ("MFMT 20240131093000 b.txt", "550")]).await;
+}
+
+/// Test of an upload whose data connection is reset, the session going on after the reply
+#[tokio::test]
+async fn test_upload_connection_reset() {
+    let mut session = TestSession::with_user(test_user("user")).await;
+    session.login("user").await;
+    let data_connection = session.passive().await;
+    assert!(session.send("STOR a.txt").await.starts_with("125"));
+    data_connection.set_zero_linger().unwrap();
+    drop(data_connection);
+    assert!(session.reply().await.unwrap().starts_with("426"));
+    assert!(session.send("NOOP").await.starts_with("200"));
+}
+
+/// Test of `HASH` over the byte ranges set by `RANG`, refusing the ones past the end of the file
+#[tokio::test]
+async fn test_hash_range() {
+    let mut session = TestSession::with_user(test_user("user")).await;
+    session.login("user").await;
+    assert!(session.store("a.txt", b"Hello, World!").await.starts_with("226"));
+    session.expect(&[("RANG 0 4", "350"), ("HASH a.txt", "213 SHA-256 0-4 "), ("RANG 0 18446744073709551615", "350"), ("HASH a.txt", "501"),
+        ("RANG 7 13", "350"), ("HASH a.txt", "501"), ("HASH a.txt", "213 SHA-256 0-12 ")]).await;
+}
+
+/// Test of `STOU` taking the name of its file before the transfer starts
+#[tokio::test]
+async fn test_unique_upload() {
+    let mut first = TestSession::start(FtpConfig { users: vec![test_user("user")], atomic_uploads: true, ..test_config() }).await;
+    let mut second = TestSession::connect(first.port).await;
+    first.login("user").await;
+    second.login("user").await;
+
+    // the second upload doesn't get the name of the first one, still being transferred
+    let mut first_data = first.passive().await;
+    assert_eq!(first.send("STOU a.txt").await, "125 FILE: a.txt");
+    let mut second_data = second.passive().await;
+    assert_eq!(second.send("STOU a.txt").await, "125 FILE: a.1.txt");
+    for (session, data, content) in [(&mut first, &mut first_data, b"first"), (&mut second, &mut second_data, b"other")] {
+        data.write_all(content).await.unwrap();
+        data.shutdown().await.unwrap();
+        assert!(session.reply().await.unwrap().starts_with("226"));
+    }
+    assert_eq!(first.send("SIZE a.txt").await, "213 5");
+    assert_eq!(first.send("SIZE a.1.txt").await, "213 5");
+}
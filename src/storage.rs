@@ -0,0 +1,536 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use crate::ftp_config::StorageConfig;
+use crate::local_storage::{file_metadata, LocalStorage};
+use crate::memory_storage::MemoryStorage;
+use crate::s3_storage::S3Storage;
+use crate::utils::normalize_path;
+
+/// Metadata of a file or directory of a storage backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMetadata {
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Unix permission bits, e.g. `0o644`
+    pub mode: u32,
+}
+
+impl FileMetadata {
+    pub fn dir(modified: SystemTime) -> Self {
+        FileMetadata { is_dir: true, size: 0, modified, mode: 0o755 }
+    }
+
+    pub fn file(size: u64, modified: SystemTime) -> Self {
+        FileMetadata { is_dir: false, size, modified, mode: 0o644 }
+    }
+}
+
+/// Entry of a directory listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub metadata: FileMetadata,
+}
+
+/// Stream of the content of a file being downloaded
+pub type FileReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// File being uploaded. The upload is only complete once `finish` succeeds, and `abort`
+/// discards what the backend can of an interrupted upload.
+#[async_trait]
+pub trait FileWriter: Send {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Making the written data durable, for the backends buffering it
+    async fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Completing the upload, returning the size of the file
+    async fn finish(self: Box<Self>) -> io::Result<u64>;
+
+    async fn abort(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Filesystem the sessions are served from. Paths are absolute virtual paths of the backend,
+/// e.g. `/docs/a.txt`, already normalized by the caller.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>>;
+
+    /// Opening a file for reading, starting at `offset`
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader>;
+
+    /// Opening a file for writing, truncating it or appending to it. With `create_new` the file
+    /// must not exist, and it is created right away so that its name is taken.
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>>;
+
+    /// Creating a directory along with its missing parents
+    async fn mkdir(&self, path: &Path) -> io::Result<()>;
+
+    /// Removing a file, or a directory with its content
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Removing an item deleted by a user, which the storages keeping a trash move to it instead.
+    /// The files the server removes itself, e.g. the temporary files of uploads, use `remove`.
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.remove(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()>;
+
+    /// Setting an extended attribute of a file, e.g. `user.sha256`, for the backends supporting them
+    async fn set_attribute(&self, _path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Extended attributes aren't supported"))
+    }
+
+    /// Getting the free space of the disk holding a path, `None` when it isn't bounded by a disk
+    async fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Checking that an upload may replace the file at a path, before it is written elsewhere
+    /// and renamed over it, for the storages refusing to overwrite files
+    async fn check_overwrite(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader of the chunks of a file produced by another task, the stream ending with the task
+pub struct ChunkReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ChunkReader {
+    pub fn new(receiver: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+        ChunkReader { receiver, chunk: vec![], position: 0 }
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.position == self.chunk.len() {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                },
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let size = buf.remaining().min(self.chunk.len() - self.position);
+        let position = self.position;
+        buf.put_slice(&self.chunk[position..position + size]);
+        self.position += size;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Storage serving a directory of another storage as its root
+pub struct ScopedStorage {
+    inner: Arc<dyn StorageBackend>,
+    root: PathBuf,
+}
+
+impl ScopedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, root: &Path) -> Self {
+        ScopedStorage { inner, root: normalize_path(root) }
+    }
+
+    fn inner_path(&self, path: &Path) -> PathBuf {
+        let path = normalize_path(path);
+        self.root.join(path.strip_prefix("/").unwrap_or(&path))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ScopedStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(&self.inner_path(path)).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(&self.inner_path(path)).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(&self.inner_path(path), offset).await
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        self.inner.open_write(&self.inner_path(path), append, create_new).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(&self.inner_path(path)).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        if normalize_path(path) == Path::new("/") {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.inner.remove(&self.inner_path(path)).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        if normalize_path(path) == Path::new("/") {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.inner.delete(&self.inner_path(path)).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(&self.inner_path(from), &self.inner_path(to)).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(&self.inner_path(path), modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(&self.inner_path(path), name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(&self.inner_path(path)).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(&self.inner_path(path)).await
+    }
+}
+
+/// Upload written to a hidden temporary file next to its destination, and renamed into place once
+/// complete so that the destination never holds a partial file
+pub struct AtomicFileWriter {
+    inner: Box<dyn FileWriter>,
+    storage: Arc<dyn StorageBackend>,
+    temp: PathBuf,
+    path: PathBuf,
+    /// Whether the destination was created empty to take its name, removed if the upload fails
+    reserved: bool,
+}
+
+impl AtomicFileWriter {
+    pub async fn open(storage: Arc<dyn StorageBackend>, path: &Path, create_new: bool) -> io::Result<Self> {
+        static UPLOADS: AtomicU64 = AtomicU64::new(0);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.{}-{}.part", name, std::process::id(), UPLOADS.fetch_add(1, Ordering::Relaxed)));
+
+        // an upload the storage would refuse to rename over its destination is refused before
+        // any data is sent
+        match create_new {
+            true => storage.open_write(path, false, true).await?.finish().await.map(|_| ())?,
+            false => storage.check_overwrite(path).await?
+        }
+        match storage.open_write(&temp, false, false).await {
+            Ok(inner) => Ok(AtomicFileWriter { inner, storage, temp, path: path.to_path_buf(), reserved: create_new }),
+            Err(err) => {
+                if create_new {
+                    storage.remove(path).await.ok();
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FileWriter for AtomicFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write(data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        let AtomicFileWriter { mut inner, storage, temp, path, reserved } = *self;
+        let result = match inner.sync().await {
+            Ok(()) => inner.finish().await,
+            Err(err) => inner.abort().await.and(Err(err))
+        };
+        let result = match result {
+            Ok(size) => storage.rename(&temp, &path).await.map(|_| size),
+            Err(err) => Err(err)
+        };
+
+        if result.is_err() {
+            storage.remove(&temp).await.ok();
+            if reserved {
+                storage.remove(&path).await.ok();
+            }
+        }
+        result
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await.ok();
+        if self.reserved {
+            self.storage.remove(&self.path).await.ok();
+        }
+        self.storage.remove(&self.temp).await
+    }
+}
+
+/// Storage writing the files of another storage with an `AtomicFileWriter`. Appending to a file
+/// keeps what it holds, so only the truncating uploads are handled.
+pub struct AtomicStorage {
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl AtomicStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>) -> Self {
+        AtomicStorage { inner }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for AtomicStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(path).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(path, offset).await
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        if append {
+            return self.inner.open_write(path, true, create_new).await;
+        }
+        Ok(Box::new(AtomicFileWriter::open(self.inner.clone(), path, create_new).await?))
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(path, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(path, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(path).await
+    }
+}
+
+/// Storage refusing every change to another storage
+pub struct ReadOnlyStorage {
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl ReadOnlyStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>) -> Self {
+        ReadOnlyStorage { inner }
+    }
+
+    fn read_only() -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, "Read-only storage")
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReadOnlyStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(path).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(path, offset).await
+    }
+
+    async fn open_write(&self, _path: &Path, _append: bool, _create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        Err(Self::read_only())
+    }
+
+    async fn mkdir(&self, _path: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn remove(&self, _path: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn set_mtime(&self, _path: &Path, _modified: SystemTime) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn set_attribute(&self, _path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(Self::read_only())
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(path).await
+    }
+}
+
+/// Function to create the storage backend of a config, `None` standing for the local filesystem
+/// of the server
+pub fn new_storage(config: &StorageConfig) -> Option<Arc<dyn StorageBackend>> {
+    match *config {
+        StorageConfig::Local => None,
+        StorageConfig::Memory => Some(Arc::new(MemoryStorage::new())),
+        StorageConfig::S3(ref config) => Some(Arc::new(S3Storage::new(config.clone()))),
+    }
+}
+
+/// Storage the server is configured with, holding the home directories and mounted folders
+#[derive(Clone)]
+pub enum StorageRoot {
+    /// Directory of the local filesystem. Home directories and mounts are host directories,
+    /// the relative ones being resolved against it.
+    Local(PathBuf),
+    /// Storage shared by the sessions, home directories and mounts being directories of it
+    Shared(Arc<dyn StorageBackend>),
+}
+
+impl StorageRoot {
+    pub fn new(config: Option<&StorageConfig>, root_dir: PathBuf) -> Self {
+        match config.and_then(new_storage) {
+            Some(storage) => StorageRoot::Shared(storage),
+            None => StorageRoot::Local(root_dir),
+        }
+    }
+
+    /// Getting a storage serving a directory of the root, the whole root for an empty path
+    pub fn open(&self, path: &Path) -> Arc<dyn StorageBackend> {
+        match *self {
+            StorageRoot::Local(ref root_dir) => Arc::new(LocalStorage::new(root_dir.join(path))),
+            StorageRoot::Shared(ref storage) if normalize_path(path) == Path::new("/") => storage.clone(),
+            StorageRoot::Shared(ref storage) => Arc::new(ScopedStorage::new(storage.clone(), path)),
+        }
+    }
+
+    pub async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        match *self {
+            StorageRoot::Local(ref root_dir) => Ok(file_metadata(&tokio::fs::metadata(root_dir.join(path)).await?)),
+            StorageRoot::Shared(ref storage) => storage.metadata(path).await,
+        }
+    }
+
+    /// Getting the host path of a path of the root, when it is on the local filesystem
+    pub fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        match *self {
+            StorageRoot::Local(ref root_dir) => Some(root_dir.join(path)),
+            StorageRoot::Shared(_) => None,
+        }
+    }
+
+    pub async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        match *self {
+            StorageRoot::Local(ref root_dir) => tokio::fs::create_dir_all(root_dir.join(path)).await,
+            StorageRoot::Shared(ref storage) => storage.mkdir(path).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_scoped_storage() {
+    let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    storage.mkdir(Path::new("/homes/user1")).await.unwrap();
+
+    let root = StorageRoot::Shared(storage.clone());
+    let home = root.open(Path::new("homes/user1"));
+    home.mkdir(Path::new("/docs")).await.unwrap();
+    home.open_write(Path::new("/../../docs/a.txt"), false, false).await.unwrap().finish().await.unwrap();
+
+    assert!(storage.metadata(Path::new("/homes/user1/docs/a.txt")).await.is_ok());
+    assert_eq!(home.list(Path::new("/")).await.unwrap()[0].name, "docs");
+    assert!(home.remove(Path::new("/")).await.is_err());
+    assert!(root.metadata(Path::new("homes")).await.unwrap().is_dir);
+
+    let read_only = ReadOnlyStorage::new(home);
+    assert!(read_only.metadata(Path::new("/docs/a.txt")).await.is_ok());
+    assert_eq!(read_only.remove(Path::new("/docs/a.txt")).await.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert!(read_only.open_write(Path::new("/b.txt"), false, false).await.is_err());
+}
+
+#[tokio::test]
+async fn test_atomic_file_writer() {
+    let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    storage.open_write(Path::new("/a.txt"), false, false).await.unwrap().finish().await.unwrap();
+
+    let mut writer = Box::new(AtomicFileWriter::open(storage.clone(), Path::new("/a.txt"), false).await.unwrap());
+    writer.write(b"Hello").await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/a.txt")).await.unwrap().size, 0);
+    let names: Vec<String> = storage.list(Path::new("/")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert!(names.iter().any(|name| name.starts_with(".a.txt.") && name.ends_with(".part")));
+
+    assert_eq!(writer.finish().await.unwrap(), 5);
+    assert_eq!(storage.metadata(Path::new("/a.txt")).await.unwrap().size, 5);
+    assert_eq!(storage.list(Path::new("/")).await.unwrap().len(), 1);
+
+    let mut writer = Box::new(AtomicFileWriter::open(storage.clone(), Path::new("/a.txt"), false).await.unwrap());
+    writer.write(b"Hi").await.unwrap();
+    writer.abort().await.unwrap();
+    assert_eq!(storage.metadata(Path::new("/a.txt")).await.unwrap().size, 5);
+    assert_eq!(storage.list(Path::new("/")).await.unwrap().len(), 1);
+
+    // a new file takes its name for the time of the upload
+    assert_eq!(AtomicFileWriter::open(storage.clone(), Path::new("/a.txt"), true).await.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    let mut writer = Box::new(AtomicFileWriter::open(storage.clone(), Path::new("/b.txt"), true).await.unwrap());
+    assert_eq!(AtomicFileWriter::open(storage.clone(), Path::new("/b.txt"), true).await.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    writer.write(b"Hello").await.unwrap();
+    assert_eq!(writer.finish().await.unwrap(), 5);
+    assert_eq!(storage.metadata(Path::new("/b.txt")).await.unwrap().size, 5);
+
+    let writer = Box::new(AtomicFileWriter::open(storage.clone(), Path::new("/c.txt"), true).await.unwrap());
+    writer.abort().await.unwrap();
+    assert_eq!(storage.list(Path::new("/")).await.unwrap().len(), 2);
+}
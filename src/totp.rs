@@ -0,0 +1,97 @@
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+
+/// Duration of a time step, in seconds
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// Function to decode a base32 (RFC 4648) secret, ignoring the case, spaces and padding
+pub fn decode_base32(secret: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in secret.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// HMAC-based one-time password (RFC 4226) of the counter
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    code % 10u32.pow(DIGITS)
+}
+
+/// Function to verify a time-based one-time code (RFC 6238) at the given unix time,
+/// accepting the codes of the previous and next time steps to allow for clock drift
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> bool {
+    let key = match decode_base32(secret) {
+        Some(key) if !key.is_empty() => key,
+        _ => return false
+    };
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let code: u32 = match code.parse() {
+        Ok(code) => code,
+        Err(_) => return false
+    };
+
+    let counter = unix_time / STEP;
+    [counter.saturating_sub(1), counter, counter + 1].iter().any(|&counter| hotp(&key, counter) == code)
+}
+
+/// Function to get the time-based one-time code of a secret at the given unix time
+#[cfg(test)]
+pub fn code(secret: &str, unix_time: u64) -> String {
+    format!("{:0width$}", hotp(&decode_base32(secret).unwrap(), unix_time / STEP), width = DIGITS as usize)
+}
+
+/// Function to split a password of the form `password123456` into the password and the code
+pub fn split_code(password: &str) -> Option<(&str, &str)> {
+    let split = password.len().checked_sub(DIGITS as usize).filter(|&split| split > 0)?;
+    if !password.is_char_boundary(split) {
+        return None;
+    }
+    let (password, code) = password.split_at(split);
+    code.bytes().all(|b| b.is_ascii_digit()).then_some((password, code))
+}
+
+#[test]
+fn test_totp() {
+    // RFC 6238 test secret "12345678901234567890"
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    assert_eq!(decode_base32(secret).unwrap(), b"12345678901234567890".to_vec());
+    assert!(verify(secret, "287082", 59));
+    assert_eq!(code(secret, 1234567890), "005924");
+    assert!(verify(secret, "081804", 1111111109));
+    assert!(verify(secret, "005924", 1234567890));
+    assert!(verify("gezd gnbv gy3t qojq gezd gnbv gy3t qojq", "005924", 1234567890 + 30));
+    assert!(!verify(secret, "005924", 1234567890 + 90));
+    assert!(!verify(secret, "5924", 1234567890));
+    assert!(!verify("not base32!", "005924", 1234567890));
+}
+
+#[test]
+fn test_split_code() {
+    assert_eq!(split_code("secret123456"), Some(("secret", "123456")));
+    assert_eq!(split_code("123456"), None);
+    assert_eq!(split_code("secret12345a"), None);
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use crate::filename_policy::unique_name;
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+use crate::utils::normalize_path;
+
+/// Directory of the deleted items, at the root of the storage they were deleted from
+pub const TRASH_DIR: &str = ".trash";
+
+/// Time between two purges of the expired items of the trashes
+pub const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Recycle bin the deleted files and directories are moved to instead of being removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrashConfig {
+    /// Days the deleted items are kept before being purged, forever without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+}
+
+/// Metadata of a deleted item, stored next to it as `<item>.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashInfo {
+    pub original_path: PathBuf,
+    pub deleted_by: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// Storage moving the items deleted by the users of another storage to its trash directory.
+/// Items deleted from a trash directory, its own or the one of a user's home, are deleted for
+/// good, and so are the files the server removes itself.
+pub struct TrashStorage {
+    inner: Arc<dyn StorageBackend>,
+    config: TrashConfig,
+    /// User the deletions are recorded for
+    username: String,
+}
+
+impl TrashStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, config: TrashConfig, username: &str) -> Self {
+        TrashStorage { inner, config, username: username.to_string() }
+    }
+
+    /// Removing the items of the trash deleted longer ago than the retention period
+    pub async fn purge(&self) -> io::Result<()> {
+        let retention_days = match self.config.retention_days {
+            Some(retention_days) => retention_days,
+            None => return Ok(())
+        };
+        let trash = Path::new("/").join(TRASH_DIR);
+        let expired = Utc::now() - Duration::days(retention_days as i64);
+
+        for entry in self.inner.list(&trash).await.unwrap_or_default() {
+            let Some(item) = entry.name.strip_suffix(".json") else { continue };
+            match read_info(self.inner.as_ref(), &trash.join(&entry.name)).await {
+                Ok(info) if info.deleted_at < expired => {
+                    match self.inner.remove(&trash.join(item)).await {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => self.inner.remove(&trash.join(&entry.name)).await?
+                    }
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
+    /// Moving an item to the trash, under a name made of the time of its deletion and its own name
+    async fn discard(&self, path: &Path) -> io::Result<()> {
+        self.inner.metadata(path).await?;
+        let trash = Path::new("/").join(TRASH_DIR);
+        self.inner.mkdir(&trash).await?;
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let item = format!("{}-{}", Utc::now().format("%Y%m%d-%H%M%S-%3f"), name);
+        let taken: Vec<String> = self.inner.list(&trash).await?.into_iter().map(|entry| entry.name).collect();
+        let item = unique_name(&item, |name| taken.iter().any(|taken| taken == name || *taken == format!("{}.json", name)));
+
+        self.inner.rename(path, &trash.join(&item)).await?;
+        let info = TrashInfo { original_path: path.to_path_buf(), deleted_by: self.username.clone(), deleted_at: Utc::now() };
+        let mut writer = self.inner.open_write(&trash.join(format!("{}.json", item)), false, false).await?;
+        writer.write(&serde_json::to_vec_pretty(&info)?).await?;
+        writer.finish().await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for TrashStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(path).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(path, offset).await
+    }
+
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        self.inner.open_write(path, append, create_new).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        let path = normalize_path(path);
+        if path.components().any(|component| component.as_os_str() == TRASH_DIR) {
+            return self.inner.remove(&path).await;
+        }
+        self.discard(&path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(path, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(path, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        self.inner.check_overwrite(path).await
+    }
+}
+
+/// Trashes of the storages the users logged in to, shared between every connection of the
+/// server, which purges their expired items periodically rather than on the login path
+#[derive(Default)]
+pub struct TrashRegistry {
+    /// Trashes by the location of their storage, the last session's one replacing the others
+    trashes: Mutex<HashMap<String, Arc<TrashStorage>>>,
+}
+
+impl TrashRegistry {
+    pub fn new() -> Self {
+        TrashRegistry::default()
+    }
+
+    pub fn register(&self, location: String, trash: Arc<TrashStorage>) {
+        self.trashes.lock().unwrap().insert(location, trash);
+    }
+
+    /// Purging every registered trash, the failures being logged
+    pub async fn purge(&self) {
+        let trashes: Vec<(String, Arc<TrashStorage>)> = self.trashes.lock().unwrap().iter()
+            .map(|(location, trash)| (location.clone(), trash.clone())).collect();
+        for (location, trash) in trashes {
+            if let Err(err) = trash.purge().await {
+                eprintln!("Unable to purge the trash of {}: {}", location, err);
+            }
+        }
+    }
+}
+
+async fn read_info(storage: &dyn StorageBackend, path: &Path) -> io::Result<TrashInfo> {
+    let mut data = vec![];
+    storage.open_read(path, 0).await?.read_to_end(&mut data).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Function to move an item of a trash directory back to where it was deleted from, returning
+/// its restored path. The trash can be anywhere in the storage, e.g. in the home of a user.
+pub async fn restore(storage: &dyn StorageBackend, item: &Path) -> io::Result<PathBuf> {
+    let item = normalize_path(item);
+    let (trash, name) = match (item.parent(), item.file_name()) {
+        (Some(trash), Some(name)) if trash.file_name().is_some_and(|dir| dir == TRASH_DIR) => (trash, name.to_string_lossy()),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not an item of a trash directory"))
+    };
+    let metadata = trash.join(format!("{}.json", name));
+    let info = read_info(storage, &metadata).await?;
+
+    // the original path is relative to the root of the storage the trash belongs to
+    let root = trash.parent().unwrap_or(Path::new("/"));
+    let original = normalize_path(&info.original_path);
+    let target = root.join(original.strip_prefix("/").unwrap_or(&original));
+    if storage.metadata(&target).await.is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Original path already exists"));
+    }
+    if let Some(parent) = target.parent() {
+        storage.mkdir(parent).await?;
+    }
+    storage.rename(&item, &target).await?;
+    storage.remove(&metadata).await?;
+    Ok(target)
+}
+
+#[tokio::test]
+async fn test_trash_storage() {
+    use crate::memory_storage::MemoryStorage;
+
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    inner.mkdir(Path::new("/alice/docs")).await.unwrap();
+    inner.open_write(Path::new("/alice/docs/a.txt"), false, false).await.unwrap().finish().await.unwrap();
+
+    // the trash of a user is at the root of their own tree
+    let home = Arc::new(crate::storage::ScopedStorage::new(inner.clone(), Path::new("/alice")));
+    let storage = TrashStorage::new(home, TrashConfig { retention_days: Some(30) }, "alice");
+    storage.delete(Path::new("/docs/a.txt")).await.unwrap();
+    assert!(inner.metadata(Path::new("/alice/docs/a.txt")).await.is_err());
+
+    let entries = inner.list(Path::new("/alice/.trash")).await.unwrap();
+    assert_eq!(entries.len(), 2);
+    let item = entries.iter().find(|entry| !entry.name.ends_with(".json")).unwrap();
+    assert!(item.name.ends_with("-a.txt"));
+    let info = read_info(inner.as_ref(), &Path::new("/alice/.trash").join(format!("{}.json", item.name))).await.unwrap();
+    assert_eq!((info.original_path, info.deleted_by), (PathBuf::from("/docs/a.txt"), "alice".to_string()));
+
+    // an admin sharing the whole root restores it
+    let restored = restore(inner.as_ref(), &Path::new("/alice/.trash").join(&item.name)).await.unwrap();
+    assert_eq!(restored, PathBuf::from("/alice/docs/a.txt"));
+    assert!(inner.list(Path::new("/alice/.trash")).await.unwrap().is_empty());
+    assert_eq!(restore(inner.as_ref(), Path::new("/alice/docs/a.txt")).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    // directories go to the trash too, and deleting from the trash deletes for good
+    storage.delete(Path::new("/docs")).await.unwrap();
+    for entry in inner.list(Path::new("/alice/.trash")).await.unwrap() {
+        storage.delete(&Path::new("/.trash").join(entry.name)).await.unwrap();
+    }
+    assert!(inner.list(Path::new("/alice/.trash")).await.unwrap().is_empty());
+
+    // the files removed by the server itself skip the trash
+    storage.open_write(Path::new("/b.txt.part"), false, false).await.unwrap().finish().await.unwrap();
+    storage.remove(Path::new("/b.txt.part")).await.unwrap();
+    assert!(inner.metadata(Path::new("/alice/b.txt.part")).await.is_err());
+    assert!(inner.list(Path::new("/alice/.trash")).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_trash_purge() {
+    use crate::memory_storage::MemoryStorage;
+
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    let storage = Arc::new(TrashStorage::new(inner.clone(), TrashConfig { retention_days: Some(7) }, "bob"));
+    for name in ["old.txt", "new.txt"] {
+        inner.open_write(&Path::new("/").join(name), false, false).await.unwrap().finish().await.unwrap();
+        storage.delete(&Path::new("/").join(name)).await.unwrap();
+    }
+
+    // backdating the deletion of the first item
+    let trash = Path::new("/.trash");
+    for entry in inner.list(trash).await.unwrap() {
+        if entry.name.ends_with("old.txt.json") {
+            let mut info = read_info(inner.as_ref(), &trash.join(&entry.name)).await.unwrap();
+            info.deleted_at -= Duration::days(8);
+            let mut writer = inner.open_write(&trash.join(&entry.name), false, false).await.unwrap();
+            writer.write(&serde_json::to_vec(&info).unwrap()).await.unwrap();
+            writer.finish().await.unwrap();
+        }
+    }
+    // the server purges the trashes the sessions registered
+    let trashes = TrashRegistry::new();
+    trashes.register("/bob".to_string(), storage);
+    trashes.purge().await;
+    let names: Vec<String> = inner.list(trash).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.iter().all(|name| name.contains("new.txt")));
+}
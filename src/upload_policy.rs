@@ -0,0 +1,241 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use crate::storage::FileWriter;
+
+/// Number of first bytes the content of the uploads is recognized from
+const SNIFF_SIZE: usize = 8;
+
+/// Kind of content recognized from the first bytes of the uploaded files
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    /// ELF, PE, Mach-O binaries and `#!` scripts
+    Executable,
+    /// zip, gzip, bzip2, xz, 7z and rar archives
+    Archive,
+    Pdf,
+    Image,
+}
+
+impl ContentType {
+    fn signatures(&self) -> &'static [&'static [u8]] {
+        match *self {
+            ContentType::Executable => &[b"\x7fELF", b"MZ", b"\xfe\xed\xfa\xce", b"\xfe\xed\xfa\xcf", b"\xce\xfa\xed\xfe", b"\xcf\xfa\xed\xfe", b"\xca\xfe\xba\xbe", b"#!"],
+            ContentType::Archive => &[b"PK\x03\x04", b"\x1f\x8b", b"BZh", b"\xfd7zXZ\x00", b"7z\xbc\xaf\x27\x1c", b"Rar!\x1a\x07"],
+            ContentType::Pdf => &[b"%PDF-"],
+            ContentType::Image => &[b"\x89PNG\r\n\x1a\n", b"\xff\xd8\xff", b"GIF87a", b"GIF89a"],
+        }
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.signatures().iter().any(|signature| data.starts_with(signature))
+    }
+}
+
+/// Rules the files uploaded under a virtual path have to follow. Extensions are compared without
+/// their dot and case, and patterns are matched against the file names with `*` and `?` wildcards.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadPolicy {
+    /// Virtual path of the folder the policy applies to, the whole tree by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Extensions allowed, any of them when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_extensions: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_extensions: Vec<String>,
+    /// Patterns the names have to match one of, any name when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_patterns: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_patterns: Vec<String>,
+    /// Contents refused whatever the name of the file, recognized from their first bytes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_content: Vec<ContentType>,
+}
+
+impl UploadPolicy {
+    pub fn applies_to(&self, path: &Path) -> bool {
+        self.path.as_ref().is_none_or(|folder| path.starts_with(Path::new("/").join(folder)))
+    }
+
+    /// Checking the name of a file before it is uploaded
+    pub fn check_name(&self, name: &str) -> Result<(), PolicyViolation> {
+        let lowercase = name.to_lowercase();
+        let has_extension = |extension: &String| lowercase.ends_with(&format!(".{}", extension.trim_start_matches('.').to_lowercase()));
+
+        if !self.allowed_extensions.is_empty() && !self.allowed_extensions.iter().any(has_extension) {
+            return Err(PolicyViolation("File type not allowed"));
+        }
+        if self.denied_extensions.iter().any(has_extension) {
+            return Err(PolicyViolation("File type not allowed"));
+        }
+        if !self.allowed_patterns.is_empty() && !self.allowed_patterns.iter().any(|pattern| glob_match(pattern, name)) {
+            return Err(PolicyViolation("File name not allowed"));
+        }
+        if self.denied_patterns.iter().any(|pattern| glob_match(pattern, name)) {
+            return Err(PolicyViolation("File name not allowed"));
+        }
+        Ok(())
+    }
+
+    /// Checking the size and content of a file as it is uploaded
+    pub fn writer(&self, inner: Box<dyn FileWriter>) -> Box<dyn FileWriter> {
+        if self.max_size.is_none() && self.denied_content.is_empty() {
+            return inner;
+        }
+        Box::new(PolicyFileWriter { inner, max_size: self.max_size, denied_content: self.denied_content.clone(), head: vec![], written: 0 })
+    }
+}
+
+/// Error of an upload breaking an `UploadPolicy`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyViolation(pub &'static str);
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl From<PolicyViolation> for io::Error {
+    fn from(violation: PolicyViolation) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, violation)
+    }
+}
+
+/// Function to get the policy violation an upload failed with, if any
+pub fn policy_violation(err: &io::Error) -> Option<PolicyViolation> {
+    err.get_ref().and_then(|err| err.downcast_ref::<PolicyViolation>()).copied()
+}
+
+/// Upload refused once it goes over the maximum size or when it starts with a denied content
+struct PolicyFileWriter {
+    inner: Box<dyn FileWriter>,
+    max_size: Option<u64>,
+    denied_content: Vec<ContentType>,
+    /// First bytes of the file, until there are enough of them to recognize its content
+    head: Vec<u8>,
+    written: u64,
+}
+
+impl PolicyFileWriter {
+    fn check_content(&self) -> io::Result<()> {
+        if self.denied_content.iter().any(|content| content.matches(&self.head)) {
+            return Err(PolicyViolation("File content not allowed").into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileWriter for PolicyFileWriter {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.head.len() < SNIFF_SIZE {
+            let size = data.len().min(SNIFF_SIZE - self.head.len());
+            self.head.extend_from_slice(&data[..size]);
+            if self.head.len() == SNIFF_SIZE {
+                self.check_content()?;
+            }
+        }
+        self.written += data.len() as u64;
+        if self.max_size.is_some_and(|max_size| self.written > max_size) {
+            return Err(PolicyViolation("File too large").into());
+        }
+        self.inner.write(data).await
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+
+    /// Checking the content of the files smaller than the recognized bytes
+    async fn finish(self: Box<Self>) -> io::Result<u64> {
+        if let Err(err) = self.check_content() {
+            self.inner.abort().await.ok();
+            return Err(err);
+        }
+        self.inner.finish().await
+    }
+
+    async fn abort(self: Box<Self>) -> io::Result<()> {
+        self.inner.abort().await
+    }
+}
+
+/// Function to match a name against a pattern with `*` (any characters) and `?` (one character)
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and of the name when it was met, to backtrack
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.csv", "report.csv"));
+    assert!(glob_match("report-????.csv", "report-2024.csv"));
+    assert!(glob_match("*backup*", "db-backup-1.sql"));
+    assert!(!glob_match("*.csv", "report.csv.exe"));
+    assert!(!glob_match("report-????.csv", "report-24.csv"));
+    assert!(glob_match("*", ""));
+}
+
+#[tokio::test]
+async fn test_upload_policy() {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::StorageBackend;
+
+    let policy: UploadPolicy = serde_json::from_str(
+        r#"{"path": "/incoming", "max_size": 10, "allowed_extensions": ["csv", ".TXT"], "denied_patterns": ["~*"], "denied_content": ["executable"]}"#
+    ).unwrap();
+    assert!(policy.applies_to(Path::new("/incoming/2024/a.csv")));
+    assert!(!policy.applies_to(Path::new("/incomings/a.csv")));
+
+    assert!(policy.check_name("report.CSV").is_ok());
+    assert!(policy.check_name("notes.txt").is_ok());
+    assert_eq!(policy.check_name("setup.exe"), Err(PolicyViolation("File type not allowed")));
+    assert_eq!(policy.check_name("~report.csv"), Err(PolicyViolation("File name not allowed")));
+
+    let storage = MemoryStorage::new();
+    let mut writer = policy.writer(storage.open_write(Path::new("/a.csv"), false, false).await.unwrap());
+    writer.write(b"a,b\n").await.unwrap();
+    writer.write(b"1,2\n").await.unwrap();
+    let err = writer.write(b"3,4\n").await.unwrap_err();
+    assert_eq!(policy_violation(&err), Some(PolicyViolation("File too large")));
+
+    // the content is recognized across the writes, and in the files shorter than its signatures
+    let mut writer = policy.writer(storage.open_write(Path::new("/b.csv"), false, false).await.unwrap());
+    writer.write(b"\x7fE").await.unwrap();
+    let err = writer.write(b"LF\x02\x01\x01\x00").await.unwrap_err();
+    assert_eq!(policy_violation(&err), Some(PolicyViolation("File content not allowed")));
+    let mut writer = policy.writer(storage.open_write(Path::new("/c.csv"), false, false).await.unwrap());
+    writer.write(b"#!").await.unwrap();
+    assert!(writer.finish().await.is_err());
+    assert_eq!(policy_violation(&io::Error::other("network")), None);
+}
@@ -7,10 +7,19 @@ use std::time::UNIX_EPOCH;
 use bytes::BytesMut;
 use chrono::{DateTime, Datelike, Local};
 use time::OffsetDateTime;
-use tokio::fs::{metadata, read_dir, DirEntry, File};
+use tokio::fs::{DirEntry, File};
+#[cfg(test)]
+use tokio::fs::read_dir;
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncReadExt;
+#[cfg(test)]
+use tokio::io::AsyncWriteExt;
 use crate::error::FtpError;
+#[cfg(test)]
+use crate::memory_storage::MemoryStorage;
+use crate::storage::FileMetadata;
+#[cfg(test)]
+use crate::storage::StorageBackend;
 
 pub const CONFIG_FILE: &str = "config.json";
 
@@ -54,62 +63,38 @@ pub fn prefix_slash(path: &mut PathBuf) {
     }
 }
 
-/// Function to add the file details metadata to the output buffer.
-/// If an error occurs when we try to get file's information, we just return and don't send its info.
-pub async fn add_file_info(path: PathBuf, out: &mut Vec<u8>) {
-    let extra = if path.is_dir() { "/" } else { "" };
-    let is_dir = if path.is_dir() { "d" } else { "-" };
+/// Function to add the details of a directory entry to the output buffer of a listing
+pub fn add_file_info(name: &str, metadata: &FileMetadata, out: &mut Vec<u8>) {
+    let is_dir = if metadata.is_dir { "d" } else { "-" };
+    let modified = metadata.modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let time = OffsetDateTime::from_unix_timestamp(modified as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
 
-    // return if we can't get the metadata
-    let metadata = match metadata(&path).await {
-        Ok(meta_data) => meta_data,
-        _ => return
-    };
-
-    let (time, file_size) = get_file_info(&metadata);
-
-    let path = match path.to_str() {
-        Some(path) => match path.split("/").last() {
-            Some(path) => path,
-            None => return
-        },
-        _ => return
-    };
-
-    // get the file permissions
-    let rights = if metadata.permissions().readonly() {
-        "r--r--r--"
+    // read-only files show up without any right but reading
+    let rights = if metadata.mode & 0o222 == 0 {
+        "r--r--r--".to_string()
     } else {
-        // getting the formatted permissions from the metadata of the file
-        &get_permissions(&metadata)
+        get_permissions(metadata.mode)
     };
 
     let file_info_str = format!(
-        "{is_dir}{rights} {links} {owner} {group} {size} {month} {day} {hour}:{min} {path}\r\n", //{extra}
+        "{is_dir}{rights} {links} {owner} {group} {size} {month} {day} {hour}:{min} {path}\r\n",
         is_dir = is_dir,
         rights = rights,
         links = 1,
         owner = "ftp-rustified",
         group = "anonymous",
-        size = file_size,
+        size = metadata.size,
         month = time.month(),
         day = time.day(),
         hour = time.hour(),
         min = time.minute(),
-        path = path,
-        // extra = extra
+        path = name,
     );
 
     out.extend(file_info_str.as_bytes());
-
-    // println!("\t\tFILE INFO ==> {}",&file_info_str);
 }
 
-pub fn get_permissions(metadata: &Metadata) -> String {
-    use std::os::unix::prelude::*;
-    let permissions = metadata.permissions();
-    let mode = permissions.mode();
-
+pub fn get_permissions(mode: u32) -> String {
     format!(
         "{}{}{}{}{}{}{}{}{}",
         if mode & 0o400 != 0 { 'r' } else { '-' },
@@ -133,6 +118,19 @@ pub fn invalid_path(path: &Path) -> bool {
     false
 }
 
+/// Function to lexically resolve the `.` and `..` components of a virtual path, which can't go above `/`
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => { normalized.pop(); },
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => ()
+        }
+    }
+    normalized
+}
+
 pub fn get_filename(path: PathBuf) -> Option<OsString> {
     path.file_name().map(|p| p.to_os_string())
 }
@@ -147,6 +145,21 @@ pub async fn create_root_dir(path: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Function to resolve the home directory template of a user, replacing `%u` by the username.
+/// Relative homes are resolved against `base`, and usernames which could escape it are refused.
+pub fn resolve_home(template: &Path, username: &str, base: &Path) -> Option<PathBuf> {
+    if username.is_empty() || username == "." || username == ".." || username.contains('/') {
+        return None;
+    }
+
+    let home = PathBuf::from(template.to_str()?.replace("%u", username));
+    if invalid_path(&home) {
+        return None;
+    }
+
+    Some(base.join(home))
+}
+
 pub fn get_current_dir() -> PathBuf {
     // env::current_dir().unwrap_or_else(|_| FtpError::Msg("Unable to get current directory\r\n".to_string()))
     env::current_dir().unwrap_or_else(|_| {
@@ -164,45 +177,80 @@ pub fn get_first_word_and_rest(input: &str) -> (Option<&str>, Option<&str>) {
     (Some(input), None)
 }
 
+#[test]
+fn test_normalize_path() {
+    assert_eq!(normalize_path(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+    assert_eq!(normalize_path(Path::new("/../../etc")), PathBuf::from("/etc"));
+    assert_eq!(normalize_path(Path::new("a/b")), PathBuf::from("/a/b"));
+}
+
+#[test]
+fn test_resolve_home() {
+    let base = Path::new("/srv/ftp");
+    assert_eq!(resolve_home(Path::new("%u"), "user1", base), Some(PathBuf::from("/srv/ftp/user1")));
+    assert_eq!(resolve_home(Path::new("homes/%u/files"), "user1", base), Some(PathBuf::from("/srv/ftp/homes/user1/files")));
+    assert_eq!(resolve_home(Path::new("/data/%u"), "user1", base), Some(PathBuf::from("/data/user1")));
+    assert_eq!(resolve_home(Path::new("%u"), "..", base), None);
+    assert_eq!(resolve_home(Path::new("%u"), "a/../../etc", base), None);
+    assert_eq!(resolve_home(Path::new("../%u"), "user1", base), None);
+}
+
+/// Function to get a temporary directory of the host for the tests, removed beforehand
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ftp-rustified-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
 #[tokio::test]
 async fn test_add_file_info() {
     let mut out  = Vec::new();
-    let path = PathBuf::from("/home/hellsent/HRs/RR/ftp-rustified/ROOT/dir1");
-
-    add_file_info(path, &mut out).await;
+    let storage = MemoryStorage::new();
+    storage.mkdir(Path::new("/dir1")).await.unwrap();
+    let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_709_647_620);
+    storage.set_mtime(Path::new("/dir1"), modified).await.unwrap();
 
-    println!("OUT ==> {:?}",String::from_utf8_lossy(&out));
+    let metadata = storage.metadata(Path::new("/dir1")).await.unwrap();
+    add_file_info("dir1", &metadata, &mut out);
 
+    assert_eq!(String::from_utf8_lossy(&out), "drwxr-xr-x 1 ftp-rustified anonymous 0 March 5 14:7 dir1\r\n");
 }
 
 #[tokio::test]
 async fn test_multiple_file() {
     let mut out = Vec::new();
-    let path = PathBuf::from("/home/hellsent/HRs/RR/ftp-rustified/ROOT");
-
-    if path.is_dir() {
-        if let Ok(mut read_dir) = read_dir(path).await {
-            while let Some(entry) = read_dir.next_entry().await.unwrap() {
-                add_file_info(entry.path(), &mut out).await;
-                println!("@@OUT ==> {:?}",String::from_utf8_lossy(&out));
-            }
-        }
+    let storage = MemoryStorage::new();
+    storage.mkdir(Path::new("/dir1")).await.unwrap();
+    let mut writer = storage.open_write(Path::new("/test.txt"), false, false).await.unwrap();
+    writer.write(b"Hello, World!").await.unwrap();
+    writer.finish().await.unwrap();
+
+    for entry in storage.list(Path::new("/")).await.unwrap() {
+        add_file_info(&entry.name, &entry.metadata, &mut out);
     }
+
+    let out = String::from_utf8_lossy(&out);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("drwxr-xr-x 1 ftp-rustified anonymous 0 ") && lines[0].ends_with(" dir1"));
+    assert!(lines[1].starts_with("-rw-r--r-- 1 ftp-rustified anonymous 13 ") && lines[1].ends_with(" test.txt"));
 }
 
 #[tokio::test]
 async fn test__read_path_2() {
-    let path = PathBuf::from("/home/hellsent/HRs/RR/ftp-rustified/ROOT");
-    // let mut out = Vec::new();
-
-    if path.is_dir() {
-        if let Ok(mut read_dir) = read_dir(path).await {
-            while let Some(entry) = read_dir.next_entry().await.unwrap() {
-                let data = get_file_info_2(entry).await;
-                println!("@@FFT ==> {:?}",data);
-            }
-        }
-    }
+    let path = test_dir("read-path");
+    std::fs::write(path.join("test.txt"), b"Hello, World!").unwrap();
+
+    let mut read_dir = read_dir(&path).await.unwrap();
+    let entry = read_dir.next_entry().await.unwrap().unwrap();
+    let data = get_file_info_2(entry).await;
+    assert!(data.starts_with("-rw"));
+    assert!(data.contains(" anonymous       13 "));
+    assert!(data.ends_with(" test.txt\r\n"));
+
+    std::fs::remove_dir_all(path).unwrap();
 }
 
 /// only for testing purposes, not realtime function for getting data from the server
@@ -251,15 +299,15 @@ async fn get_file_info_2(entry: DirEntry) -> String {
 
 #[tokio::test]
 async fn test_file_handling() {
-    match File::create_new("/home/hellsent/HRs/RR/ftp-rustified/ROOT/test.txt").await {
-        Ok(mut file ) => {
-            let mut data = Vec::new();
-            data.extend_from_slice(b"Hello, World!");
-            file.write_all(&data).await.unwrap();
-        },
-        Err(err) => {
-
-            eprintln!("Error creating file: {}", err);
-        }
-    }
-}
\ No newline at end of file
+    let dir = test_dir("file-handling");
+    let path = dir.join("test.txt");
+
+    let mut file = File::create_new(&path).await.unwrap();
+    file.write_all(b"Hello, World!").await.unwrap();
+    file.flush().await.unwrap();
+
+    assert_eq!(get_content(&path).await, Some("Hello, World!".to_string()));
+    assert!(File::create_new(&path).await.is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
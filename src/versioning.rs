@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::filename_policy::unique_name;
+use crate::storage::{FileEntry, FileMetadata, FileReader, FileWriter, StorageBackend};
+
+/// Directory holding the previous versions of the files of its parent, one subdirectory per file
+pub const VERSIONS_DIR: &str = ".versions";
+
+/// What happens to a file replaced by an upload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OverwritePolicy {
+    #[default]
+    Allow,
+    /// Refusing the uploads replacing a file
+    Deny,
+    /// Keeping the last `keep` versions of the file in the `.versions` directory next to it
+    Versions { keep: usize },
+    /// Renaming the file with the time of the upload, e.g. `report.20240131-093000.csv`
+    Rename,
+}
+
+/// Storage applying an overwrite policy to the files replaced in another storage, by uploads
+/// or by renames
+pub struct VersioningStorage {
+    inner: Arc<dyn StorageBackend>,
+    policy: OverwritePolicy,
+    /// Files created empty to take their name, replaced by a rename once uploaded
+    reserved: Mutex<HashSet<PathBuf>>,
+}
+
+impl VersioningStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, policy: OverwritePolicy) -> Self {
+        VersioningStorage { inner, policy, reserved: Mutex::new(HashSet::new()) }
+    }
+
+    /// Moving the file at a path out of the way according to the policy, before it gets replaced
+    async fn preserve(&self, path: &Path) -> io::Result<()> {
+        if !matches!(self.inner.metadata(path).await, Ok(metadata) if !metadata.is_dir) {
+            return Ok(());
+        }
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name.to_string_lossy().to_string()),
+            _ => return Ok(())
+        };
+        match self.policy {
+            OverwritePolicy::Allow => Ok(()),
+            OverwritePolicy::Deny => Err(io::Error::new(io::ErrorKind::AlreadyExists, "Overwriting files is not allowed")),
+            OverwritePolicy::Rename => {
+                let timestamp = Utc::now().format("%Y%m%d-%H%M%S");
+                let renamed = match name.rfind('.') {
+                    Some(index) if index > 0 => format!("{}.{}{}", &name[..index], timestamp, &name[index..]),
+                    _ => format!("{}.{}", name, timestamp)
+                };
+                let taken = self.names(parent).await;
+                self.inner.rename(path, &parent.join(unique_name(&renamed, |name| taken.iter().any(|taken| taken == name)))).await
+            },
+            OverwritePolicy::Versions { keep: 0 } => Ok(()),
+            OverwritePolicy::Versions { keep } => {
+                let versions = parent.join(VERSIONS_DIR).join(&name);
+                let version = Utc::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+                self.inner.mkdir(&versions).await?;
+                let taken = self.names(&versions).await;
+                self.inner.rename(path, &versions.join(unique_name(&version, |name| taken.iter().any(|taken| taken == name)))).await?;
+
+                // the names of the versions sort by date
+                let mut names = self.names(&versions).await;
+                names.sort();
+                for name in names.iter().take(names.len().saturating_sub(keep)) {
+                    self.inner.remove(&versions.join(name)).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn names(&self, dir: &Path) -> Vec<String> {
+        self.inner.list(dir).await.unwrap_or_default().into_iter().map(|entry| entry.name).collect()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for VersioningStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<FileEntry>> {
+        self.inner.list(path).await
+    }
+
+    async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        self.inner.open_read(path, offset).await
+    }
+
+    /// Appending to a file keeps it, and new files don't replace any, so only the truncating
+    /// uploads are handled
+    async fn open_write(&self, path: &Path, append: bool, create_new: bool) -> io::Result<Box<dyn FileWriter>> {
+        if create_new {
+            let writer = self.inner.open_write(path, append, true).await?;
+            self.reserved.lock().unwrap().insert(path.to_path_buf());
+            return Ok(writer);
+        }
+        if !append {
+            self.preserve(path).await?;
+        }
+        self.inner.open_write(path, append, false).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        self.reserved.lock().unwrap().remove(path);
+        self.inner.remove(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // the file taking the name of an upload is replaced by it
+        let reserved = self.reserved.lock().unwrap().remove(to);
+        if !reserved && self.inner.metadata(from).await.is_ok_and(|metadata| !metadata.is_dir) {
+            self.preserve(to).await?;
+        }
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        self.inner.set_mtime(path, modified).await
+    }
+
+    async fn set_attribute(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_attribute(path, name, value).await
+    }
+
+    async fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path).await
+    }
+
+    async fn check_overwrite(&self, path: &Path) -> io::Result<()> {
+        let exists = matches!(self.inner.metadata(path).await, Ok(metadata) if !metadata.is_dir);
+        if exists && self.policy == OverwritePolicy::Deny && !self.reserved.lock().unwrap().contains(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Overwriting files is not allowed"));
+        }
+        self.inner.check_overwrite(path).await
+    }
+}
+
+#[tokio::test]
+async fn test_versioning_storage() {
+    use std::path::PathBuf;
+    use crate::memory_storage::MemoryStorage;
+
+    async fn upload(storage: &dyn StorageBackend, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut writer = storage.open_write(Path::new(path), false, false).await?;
+        writer.write(data).await?;
+        writer.finish().await.map(|_| ())
+    }
+
+    let inner: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+    inner.mkdir(Path::new("/reports")).await.unwrap();
+
+    let denied = VersioningStorage::new(inner.clone(), OverwritePolicy::Deny);
+    upload(&denied, "/reports/q1.csv", b"1").await.unwrap();
+    assert_eq!(upload(&denied, "/reports/q1.csv", b"2").await.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    assert!(denied.open_write(Path::new("/reports/q1.csv"), true, false).await.is_ok());
+
+    // the pruned versions don't go to the trash
+    let trash = Arc::new(crate::trash::TrashStorage::new(inner.clone(), crate::trash::TrashConfig::default(), "alice"));
+    let versioned = VersioningStorage::new(trash, OverwritePolicy::Versions { keep: 2 });
+    for data in [b"2", b"3", b"4"] {
+        upload(&versioned, "/reports/q1.csv", data).await.unwrap();
+    }
+    let versions = PathBuf::from("/reports/.versions/q1.csv");
+    let names: Vec<String> = inner.list(&versions).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names.len(), 2);
+    let mut sizes = 0;
+    for name in &names {
+        sizes += inner.metadata(&versions.join(name)).await.unwrap().size;
+    }
+    assert_eq!(sizes, 2);
+    assert!(inner.metadata(Path::new("/.trash")).await.is_err());
+
+    let renamed = VersioningStorage::new(inner.clone(), OverwritePolicy::Rename);
+    upload(&renamed, "/reports/q1.csv", b"5").await.unwrap();
+    upload(&renamed, "/reports/q1.csv", b"6").await.unwrap();
+    let names: Vec<String> = inner.list(Path::new("/reports")).await.unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names.iter().filter(|name| name.starts_with("q1.") && name.ends_with(".csv") && *name != "q1.csv").count(), 2);
+
+    // renames replacing a file keep it as well
+    upload(inner.as_ref(), "/reports/q2.csv", b"7").await.unwrap();
+    upload(inner.as_ref(), "/reports/draft.csv", b"8").await.unwrap();
+    assert_eq!(denied.rename(Path::new("/reports/draft.csv"), Path::new("/reports/q2.csv")).await.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    versioned.rename(Path::new("/reports/draft.csv"), Path::new("/reports/q2.csv")).await.unwrap();
+    assert_eq!(inner.list(Path::new("/reports/.versions/q2.csv")).await.unwrap().len(), 1);
+
+    // the empty file taking the name of an atomic upload is replaced without being kept
+    let atomic = crate::storage::AtomicStorage::new(Arc::new(VersioningStorage::new(inner.clone(), OverwritePolicy::Deny)));
+    let mut writer = atomic.open_write(Path::new("/reports/q3.csv"), false, true).await.unwrap();
+    writer.write(b"9").await.unwrap();
+    writer.finish().await.unwrap();
+    assert_eq!(inner.metadata(Path::new("/reports/q3.csv")).await.unwrap().size, 1);
+    // and the overwrites the policy denies are refused before any data is written
+    assert_eq!(atomic.open_write(Path::new("/reports/q3.csv"), false, false).await.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(inner.list(Path::new("/reports")).await.unwrap().iter().filter(|entry| entry.name.ends_with(".part")).count(), 0);
+    upload(&atomic, "/reports/q4.csv", b"11").await.unwrap();
+}